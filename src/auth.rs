@@ -0,0 +1,375 @@
+//! SASL mechanism computations, kept free of I/O so they run on wasm32 and native alike.
+
+use crate::utils::form_urlencode;
+use base64::{engine::general_purpose::STANDARD as B64, Engine};
+use hmac::{Hmac, Mac};
+use md5::Md5;
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
+use std::future::Future;
+use std::pin::Pin;
+use std::rc::Rc;
+
+/// CRAM-MD5 reply (RFC 2195): `username SP hex(HMAC-MD5(password, challenge))`.
+///
+/// The caller base64-encodes the result before sending it.
+pub fn cram_md5_response(username: &str, password: &str, challenge: &[u8]) -> String {
+    let mut mac =
+        Hmac::<Md5>::new_from_slice(password.as_bytes()).expect("HMAC accepts keys of any size");
+    mac.update(challenge);
+    let digest: String = mac
+        .finalize()
+        .into_bytes()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect();
+    format!("{} {}", username, digest)
+}
+
+/// Hash function of a SCRAM mechanism.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ScramHash {
+    Sha1,
+    Sha256,
+}
+
+impl ScramHash {
+    pub fn mechanism(self) -> &'static str {
+        match self {
+            ScramHash::Sha1 => "SCRAM-SHA-1",
+            ScramHash::Sha256 => "SCRAM-SHA-256",
+        }
+    }
+
+    fn hmac(self, key: &[u8], data: &[u8]) -> Vec<u8> {
+        match self {
+            ScramHash::Sha1 => {
+                let mut mac = Hmac::<Sha1>::new_from_slice(key).expect("HMAC accepts any key");
+                mac.update(data);
+                mac.finalize().into_bytes().to_vec()
+            }
+            ScramHash::Sha256 => {
+                let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts any key");
+                mac.update(data);
+                mac.finalize().into_bytes().to_vec()
+            }
+        }
+    }
+
+    fn digest(self, data: &[u8]) -> Vec<u8> {
+        match self {
+            ScramHash::Sha1 => Sha1::digest(data).to_vec(),
+            ScramHash::Sha256 => Sha256::digest(data).to_vec(),
+        }
+    }
+
+    /// `Hi()` from RFC 5802, i.e. PBKDF2 with this HMAC.
+    fn hi(self, password: &[u8], salt: &[u8], iterations: u32) -> Vec<u8> {
+        let mut block = salt.to_vec();
+        block.extend_from_slice(&1u32.to_be_bytes());
+        let mut u = self.hmac(password, &block);
+        let mut result = u.clone();
+        for _ in 1..iterations {
+            u = self.hmac(password, &u);
+            result.iter_mut().zip(&u).for_each(|(r, x)| *r ^= x);
+        }
+        result
+    }
+}
+
+/// Client side of a SCRAM exchange (RFC 5802 / RFC 7677), without channel binding.
+pub struct ScramClient {
+    hash: ScramHash,
+    password: String,
+    nonce: String,
+    client_first_bare: String,
+    server_signature: Option<Vec<u8>>,
+}
+
+impl ScramClient {
+    /// New exchange with a random nonce.
+    pub fn new(hash: ScramHash, username: &str, password: &str) -> Result<Self, String> {
+        let mut bytes = [0u8; 24];
+        getrandom::getrandom(&mut bytes).map_err(|e| format!("nonce generation failed: {}", e))?;
+        Ok(Self::with_nonce(
+            hash,
+            username,
+            password,
+            &B64.encode(bytes),
+        ))
+    }
+
+    pub fn with_nonce(hash: ScramHash, username: &str, password: &str, nonce: &str) -> Self {
+        let username = username.replace('=', "=3D").replace(',', "=2C");
+        Self {
+            hash,
+            password: password.to_string(),
+            nonce: nonce.to_string(),
+            client_first_bare: format!("n={},r={}", username, nonce),
+            server_signature: None,
+        }
+    }
+
+    /// `client-first-message` (GS2 header `n,,`: no channel binding, no authzid).
+    pub fn client_first(&self) -> String {
+        format!("n,,{}", self.client_first_bare)
+    }
+
+    /// Builds `client-final-message` from the server's `server-first-message`.
+    pub fn client_final(&mut self, server_first: &str) -> Result<String, String> {
+        let mut nonce = None;
+        let mut salt = None;
+        let mut iterations = None;
+        for attr in server_first.split(',') {
+            match attr.split_once('=') {
+                Some(("r", v)) => nonce = Some(v),
+                Some(("s", v)) => {
+                    salt = Some(
+                        B64.decode(v)
+                            .map_err(|_| "invalid SCRAM salt".to_string())?,
+                    )
+                }
+                Some(("i", v)) => iterations = v.parse::<u32>().ok(),
+                Some(("m", _)) => return Err("unsupported SCRAM extension".into()),
+                Some(("e", v)) => return Err(format!("SCRAM server error: {}", v)),
+                _ => {}
+            }
+        }
+        let (nonce, salt, iterations) = match (nonce, salt, iterations) {
+            (Some(n), Some(s), Some(i)) if i > 0 => (n, s, i),
+            _ => {
+                return Err(format!(
+                    "malformed SCRAM server-first message: {}",
+                    server_first
+                ))
+            }
+        };
+        if !nonce.starts_with(&self.nonce) || nonce.len() == self.nonce.len() {
+            return Err("SCRAM server nonce does not extend the client nonce".into());
+        }
+
+        let without_proof = format!("c=biws,r={}", nonce);
+        let auth_message = format!(
+            "{},{},{}",
+            self.client_first_bare, server_first, without_proof
+        );
+        let salted = self.hash.hi(self.password.as_bytes(), &salt, iterations);
+        let client_key = self.hash.hmac(&salted, b"Client Key");
+        let stored_key = self.hash.digest(&client_key);
+        let client_signature = self.hash.hmac(&stored_key, auth_message.as_bytes());
+        let proof: Vec<u8> = client_key
+            .iter()
+            .zip(&client_signature)
+            .map(|(k, s)| k ^ s)
+            .collect();
+        let server_key = self.hash.hmac(&salted, b"Server Key");
+        self.server_signature = Some(self.hash.hmac(&server_key, auth_message.as_bytes()));
+        Ok(format!("{},p={}", without_proof, B64.encode(proof)))
+    }
+
+    /// Checks the server signature in `server-final-message`.
+    pub fn verify_server_final(&self, server_final: &str) -> Result<(), String> {
+        if let Some(e) = server_final.strip_prefix("e=") {
+            return Err(format!("SCRAM server error: {}", e));
+        }
+        let expected = self
+            .server_signature
+            .as_ref()
+            .ok_or_else(|| "SCRAM exchange out of order".to_string())?;
+        let signature = server_final
+            .split(',')
+            .find_map(|a| a.strip_prefix("v="))
+            .and_then(|v| B64.decode(v).ok())
+            .ok_or_else(|| format!("malformed SCRAM server-final message: {}", server_final))?;
+        if &signature != expected {
+            return Err("SCRAM server signature mismatch".into());
+        }
+        Ok(())
+    }
+}
+
+/// XOAUTH2 initial client response (before base64):
+/// `user=<user>^Aauth=Bearer <token>^A^A`.
+pub fn xoauth2_initial_response(username: &str, access_token: &str) -> String {
+    format!("user={}\x01auth=Bearer {}\x01\x01", username, access_token)
+}
+
+/// OAUTHBEARER initial client response (RFC 7628 section 3.1, before base64).
+pub fn oauthbearer_initial_response(
+    username: &str,
+    host: &str,
+    port: u16,
+    access_token: &str,
+) -> String {
+    // `,` and `=` in the authzid must be escaped per the GS2 header rules (RFC 5801).
+    let authzid = username.replace('=', "=3D").replace(',', "=2C");
+    format!(
+        "n,a={},\x01host={}\x01port={}\x01auth=Bearer {}\x01\x01",
+        authzid, host, port, access_token
+    )
+}
+
+/// Summarises the JSON error a server sends in a 334 challenge after rejecting a bearer
+/// token, e.g. `{"status":"401","schemes":"bearer","scope":"..."}`.
+pub fn oauth_error_message(payload: &[u8]) -> String {
+    match serde_json::from_slice::<serde_json::Value>(payload) {
+        Ok(json) => match json.get("status").and_then(|s| s.as_str()) {
+            Some(status) => format!("token rejected (status {})", status),
+            None => format!("token rejected ({})", json),
+        },
+        Err(_) => format!("token rejected ({})", String::from_utf8_lossy(payload)),
+    }
+}
+
+pub type TokenFuture = Pin<Box<dyn Future<Output = Result<String, worker::Error>>>>;
+
+/// Callback returning a fresh OAuth 2.0 access token for a username.
+#[derive(Clone)]
+pub struct TokenProvider(Rc<dyn Fn(String) -> TokenFuture>);
+
+impl TokenProvider {
+    pub fn new<F, Fut>(f: F) -> Self
+    where
+        F: Fn(String) -> Fut + 'static,
+        Fut: Future<Output = Result<String, worker::Error>> + 'static,
+    {
+        Self(Rc::new(move |username| Box::pin(f(username))))
+    }
+
+    /// Refresh tokens with the `refresh_token` grant against `endpoint`.
+    pub fn from_endpoint(endpoint: OAuth2TokenEndpoint) -> Self {
+        let endpoint = Rc::new(endpoint);
+        Self::new(move |_username| {
+            let endpoint = endpoint.clone();
+            async move { endpoint.refresh().await }
+        })
+    }
+
+    pub async fn fetch_token(&self, username: &str) -> Result<String, worker::Error> {
+        (self.0)(username.to_string()).await
+    }
+}
+
+impl std::fmt::Debug for TokenProvider {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("TokenProvider")
+    }
+}
+
+/// OAuth 2.0 token endpoint used to exchange a refresh token for an access token
+/// (e.g. `https://oauth2.googleapis.com/token`).
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct OAuth2TokenEndpoint {
+    pub token_url: String,
+    pub client_id: String,
+    pub client_secret: Option<String>,
+    pub refresh_token: String,
+    pub scope: Option<String>,
+}
+
+#[derive(serde::Deserialize)]
+struct TokenResponse {
+    access_token: String,
+}
+
+impl OAuth2TokenEndpoint {
+    /// POST the `refresh_token` grant and return the new access token (uses `worker::Fetch`).
+    pub async fn refresh(&self) -> Result<String, worker::Error> {
+        let mut params = vec![
+            ("grant_type", "refresh_token"),
+            ("refresh_token", self.refresh_token.as_str()),
+            ("client_id", self.client_id.as_str()),
+        ];
+        if let Some(ref secret) = self.client_secret {
+            params.push(("client_secret", secret));
+        }
+        if let Some(ref scope) = self.scope {
+            params.push(("scope", scope));
+        }
+
+        let headers = worker::Headers::new();
+        headers.set("Content-Type", "application/x-www-form-urlencoded")?;
+        let mut init = worker::RequestInit::new();
+        init.with_method(worker::Method::Post)
+            .with_headers(headers)
+            .with_body(Some(form_urlencode(&params).into()));
+        let request = worker::Request::new_with_init(&self.token_url, &init)?;
+        let mut response = worker::Fetch::Request(request).send().await?;
+        if response.status_code() != 200 {
+            let body = response.text().await.unwrap_or_default();
+            return Err(worker::Error::RustError(format!(
+                "Token refresh failed ({}): {}",
+                response.status_code(),
+                body
+            )));
+        }
+        let token: TokenResponse = response.json().await?;
+        Ok(token.access_token)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cram_md5_rfc2195_example() {
+        let response = cram_md5_response(
+            "tim",
+            "tanstaaftanstaaf",
+            b"<1896.697170952@postoffice.reston.mci.net>",
+        );
+        assert_eq!(response, "tim b913a602c7eda7a495b4e6e7334d3890");
+    }
+
+    #[test]
+    fn test_scram_sha1_rfc5802_example() {
+        let mut client = ScramClient::with_nonce(
+            ScramHash::Sha1,
+            "user",
+            "pencil",
+            "fyko+d2lbbFgONRv9qkxdawL",
+        );
+        assert_eq!(
+            client.client_first(),
+            "n,,n=user,r=fyko+d2lbbFgONRv9qkxdawL"
+        );
+        let client_final = client
+            .client_final("r=fyko+d2lbbFgONRv9qkxdawL3rfcNHYJY1ZVvWVs7j,s=QSXCR+Q6sek8bf92,i=4096")
+            .unwrap();
+        assert_eq!(
+            client_final,
+            "c=biws,r=fyko+d2lbbFgONRv9qkxdawL3rfcNHYJY1ZVvWVs7j,p=v0X8v3Bz2T0CJGbJQyF0X+HI4Ts="
+        );
+        assert!(client
+            .verify_server_final("v=rmF9pqV8S7suAoZWja4dJRkFsKQ=")
+            .is_ok());
+        assert!(client
+            .verify_server_final("v=AAAAAAAAAAAAAAAAAAAAAAAAAAA=")
+            .is_err());
+    }
+
+    #[test]
+    fn test_scram_sha256_rfc7677_example() {
+        let mut client =
+            ScramClient::with_nonce(ScramHash::Sha256, "user", "pencil", "rOprNGfwEbeRWgbNEkqO");
+        let client_final = client
+            .client_final("r=rOprNGfwEbeRWgbNEkqO%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0,s=W22ZaJ0SNY7soEsUEjb6gQ==,i=4096")
+            .unwrap();
+        assert_eq!(
+            client_final,
+            "c=biws,r=rOprNGfwEbeRWgbNEkqO%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0,p=dHzbZapWIk4jUhN+Ute9ytag9zjfMHgsqmmiz7AndVQ="
+        );
+        assert!(client
+            .verify_server_final("v=6rriTRBi23WpRR/wtup+mMhUZUn/dB5nLTJRsjl95G4=")
+            .is_ok());
+    }
+
+    #[test]
+    fn test_oauthbearer_initial_response() {
+        assert_eq!(
+            oauthbearer_initial_response("user@example.com", "server.example.com", 587, "vF9dft4qmT"),
+            "n,a=user@example.com,\x01host=server.example.com\x01port=587\x01auth=Bearer vF9dft4qmT\x01\x01"
+        );
+    }
+}
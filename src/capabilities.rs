@@ -0,0 +1,178 @@
+//! ESMTP extensions advertised in the EHLO reply (RFC 5321 section 4.1.1.1).
+
+use crate::reply::SmtpReply;
+use crate::utils::parse_rfc3339;
+use std::collections::BTreeMap;
+
+/// Typed view of an EHLO reply. Keywords are matched per line, case-insensitively.
+#[derive(Debug, Clone, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct ServerCapabilities {
+    /// Domain from the first EHLO line.
+    pub domain: String,
+    /// SASL mechanisms from `AUTH` (upper-case).
+    pub auth: Vec<String>,
+    /// `SIZE` limit in bytes; `Some(0)` means the extension is present without a limit.
+    pub size: Option<u64>,
+    pub eight_bit_mime: bool,
+    pub smtp_utf8: bool,
+    pub pipelining: bool,
+    pub chunking: bool,
+    pub dsn: bool,
+    pub start_tls: bool,
+    pub enhanced_status_codes: bool,
+    pub require_tls: bool,
+    /// `FUTURERELEASE` (RFC 4865): how far ahead a message may be held.
+    pub future_release: Option<FutureRelease>,
+    /// `MT-PRIORITY` (RFC 6710) with its priority assignment policy, e.g. `MIXER`; empty
+    /// when the server names none.
+    pub mt_priority: Option<String>,
+    /// `DELIVERBY` (RFC 2852) minimum by-time in seconds; `Some(0)` means no minimum.
+    pub deliver_by: Option<u64>,
+    /// Every other extension: upper-case keyword to its parameters.
+    pub extensions: BTreeMap<String, Vec<String>>,
+}
+
+/// Limits advertised with `FUTURERELEASE`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct FutureRelease {
+    /// Longest `HOLDFOR`, in seconds.
+    pub max_interval_secs: u64,
+    /// Latest `HOLDUNTIL`, in Unix milliseconds, when the server sent a valid one.
+    pub max_until_ms: Option<u64>,
+}
+
+impl ServerCapabilities {
+    /// Parse a full EHLO reply (`250-domain`, `250-KEYWORD params`, ..., `250 KEYWORD`).
+    pub fn parse(response: &str) -> Self {
+        Self::from_lines(
+            response
+                .lines()
+                .filter(|l| l.len() >= 3 && l.is_char_boundary(3) && l[..3].starts_with('2'))
+                .map(|l| l.get(4..).unwrap_or("")),
+        )
+    }
+
+    /// Same as [`parse`](Self::parse), from an already parsed reply.
+    pub fn from_reply(reply: &SmtpReply) -> Self {
+        Self::from_lines(reply.lines.iter().map(String::as_str))
+    }
+
+    fn from_lines<'a>(lines: impl Iterator<Item = &'a str>) -> Self {
+        let mut caps = Self::default();
+        for (i, line) in lines.map(str::trim).enumerate() {
+            if i == 0 {
+                caps.domain = line.split_whitespace().next().unwrap_or("").to_string();
+                continue;
+            }
+            let mut parts = line.split_whitespace();
+            let Some(keyword) = parts.next() else {
+                continue;
+            };
+            let keyword = keyword.to_uppercase();
+            let params: Vec<String> = parts.map(str::to_string).collect();
+            caps.apply(&keyword, params);
+        }
+        caps
+    }
+
+    fn apply(&mut self, keyword: &str, params: Vec<String>) {
+        match keyword {
+            // `AUTH=LOGIN PLAIN` is the pre-standard form some servers still send.
+            "AUTH" => self.add_auth(&params),
+            k if k.starts_with("AUTH=") => {
+                let mut all = vec![k["AUTH=".len()..].to_string()];
+                all.extend(params);
+                self.add_auth(&all);
+            }
+            "SIZE" => {
+                self.size = Some(params.first().and_then(|p| p.parse().ok()).unwrap_or(0));
+            }
+            "8BITMIME" => self.eight_bit_mime = true,
+            "SMTPUTF8" => self.smtp_utf8 = true,
+            "PIPELINING" => self.pipelining = true,
+            "CHUNKING" => self.chunking = true,
+            "DSN" => self.dsn = true,
+            "STARTTLS" => self.start_tls = true,
+            "ENHANCEDSTATUSCODES" => self.enhanced_status_codes = true,
+            "REQUIRETLS" => self.require_tls = true,
+            "MT-PRIORITY" => {
+                self.mt_priority = Some(params.first().cloned().unwrap_or_default());
+            }
+            "DELIVERBY" => {
+                self.deliver_by = Some(params.first().and_then(|p| p.parse().ok()).unwrap_or(0));
+            }
+            "FUTURERELEASE" => {
+                self.future_release = Some(FutureRelease {
+                    max_interval_secs: params.first().and_then(|p| p.parse().ok()).unwrap_or(0),
+                    max_until_ms: params.get(1).and_then(|p| parse_rfc3339(p)),
+                });
+            }
+            _ => {
+                self.extensions.insert(keyword.to_string(), params);
+            }
+        }
+    }
+
+    fn add_auth(&mut self, mechanisms: &[String]) {
+        for m in mechanisms {
+            let m = m.to_uppercase();
+            if !m.is_empty() && !self.auth.contains(&m) {
+                self.auth.push(m);
+            }
+        }
+    }
+
+    /// Whether the server offers the SASL `mechanism` (e.g. `"PLAIN"`).
+    pub fn supports_auth(&self, mechanism: &str) -> bool {
+        self.auth.iter().any(|m| m.eq_ignore_ascii_case(mechanism))
+    }
+
+    /// Parameters of an extension not covered by a typed field.
+    pub fn extension(&self, keyword: &str) -> Option<&[String]> {
+        self.extensions
+            .get(&keyword.to_uppercase())
+            .map(Vec::as_slice)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_ehlo() {
+        let caps = ServerCapabilities::parse(
+            "250-dsn.plain.example.com Hello PLAIN\r\n\
+             250-SIZE 35882577\r\n\
+             250-8BITMIME\r\n\
+             250-AUTH LOGIN PLAIN XOAUTH2\r\n\
+             250-AUTH=LOGIN\r\n\
+             250-ENHANCEDSTATUSCODES\r\n\
+             250-PIPELINING\r\n\
+             250-X-CUSTOM a b\r\n\
+             250-FUTURERELEASE 604800 2026-10-03T09:00:00Z\r\n\
+             250-MT-PRIORITY MIXER\r\n\
+             250-DELIVERBY 240\r\n\
+             250 SMTPUTF8\r\n",
+        );
+        assert_eq!(caps.domain, "dsn.plain.example.com");
+        assert_eq!(caps.size, Some(35_882_577));
+        assert_eq!(caps.auth, vec!["LOGIN", "PLAIN", "XOAUTH2"]);
+        assert!(caps.eight_bit_mime && caps.pipelining && caps.smtp_utf8);
+        assert!(caps.enhanced_status_codes);
+        assert!(!caps.dsn && !caps.start_tls && !caps.chunking);
+        assert_eq!(
+            caps.future_release,
+            Some(FutureRelease {
+                max_interval_secs: 604_800,
+                max_until_ms: Some(1_791_018_000_000),
+            })
+        );
+        assert_eq!(caps.mt_priority.as_deref(), Some("MIXER"));
+        assert_eq!(caps.deliver_by, Some(240));
+        assert_eq!(
+            caps.extension("x-custom"),
+            Some(&["a".to_string(), "b".to_string()][..])
+        );
+    }
+}
@@ -0,0 +1,140 @@
+//! Line-buffered SMTP reply decoder (RFC 5321 section 4.2).
+
+/// Largest reply accepted, continuation lines included.
+pub const DEFAULT_MAX_REPLY_SIZE: usize = 64 * 1024;
+
+/// Splits bytes read from the server into complete replies. Bytes past the end of a
+/// reply stay buffered for the next one, so pipelined replies that arrive in one read
+/// are all kept.
+#[derive(Debug)]
+pub struct ReplyCodec {
+    buf: Vec<u8>,
+    max_reply_size: usize,
+}
+
+impl Default for ReplyCodec {
+    fn default() -> Self {
+        Self::new(DEFAULT_MAX_REPLY_SIZE)
+    }
+}
+
+impl ReplyCodec {
+    pub fn new(max_reply_size: usize) -> Self {
+        Self {
+            buf: Vec::new(),
+            max_reply_size,
+        }
+    }
+
+    pub fn extend(&mut self, bytes: &[u8]) {
+        self.buf.extend_from_slice(bytes);
+    }
+
+    /// Drops buffered bytes (e.g. after STARTTLS, where they must not be trusted).
+    pub fn clear(&mut self) {
+        self.buf.clear();
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.buf.is_empty()
+    }
+
+    /// Takes the next complete reply off the buffer, `None` until it has fully arrived.
+    /// Text that is not UTF-8 is decoded lossily.
+    pub fn next_reply(&mut self) -> Result<Option<String>, String> {
+        let mut start = 0;
+        let mut code = None;
+        while let Some(pos) = self.buf[start..].iter().position(|&b| b == b'\n') {
+            let line = &self.buf[start..start + pos];
+            let line = line.strip_suffix(b"\r").unwrap_or(line);
+            start += pos + 1;
+            if start > self.max_reply_size {
+                return Err(self.too_large());
+            }
+            let line_code = match line.get(..3) {
+                Some(c) if c.iter().all(u8::is_ascii_digit) => c,
+                _ => return Err(format!("Invalid SMTP reply line: {}", lossy(line))),
+            };
+            match code {
+                None => code = Some(line_code.to_vec()),
+                Some(ref c) if c != line_code => {
+                    return Err(format!(
+                        "Reply code changed within a reply: {} then {}",
+                        lossy(c),
+                        lossy(line_code)
+                    ))
+                }
+                Some(_) => {}
+            }
+            if line.get(3) != Some(&b'-') {
+                let raw: Vec<u8> = self.buf.drain(..start).collect();
+                return Ok(Some(lossy(&raw)));
+            }
+        }
+        if self.buf.len() > self.max_reply_size {
+            return Err(self.too_large());
+        }
+        Ok(None)
+    }
+
+    /// At end of stream: the reply still buffered, accepted without its final CRLF.
+    pub fn finish(&mut self) -> Result<Option<String>, String> {
+        if self.buf.is_empty() {
+            return Ok(None);
+        }
+        if !self.buf.ends_with(b"\n") {
+            self.buf.extend_from_slice(b"\r\n");
+        }
+        match self.next_reply()? {
+            Some(reply) => Ok(Some(reply)),
+            None => Err("Connection closed in the middle of a reply".into()),
+        }
+    }
+
+    fn too_large(&mut self) -> String {
+        self.buf.clear();
+        format!("SMTP reply exceeds {} bytes", self.max_reply_size)
+    }
+}
+
+fn lossy(bytes: &[u8]) -> String {
+    String::from_utf8_lossy(bytes).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_split_and_pipelined_replies() {
+        let mut codec = ReplyCodec::default();
+        // "é" split across two reads, and a second reply in the same read
+        codec.extend(b"250-mx \xc3");
+        assert_eq!(codec.next_reply(), Ok(None));
+        codec.extend(b"\xa9t\xe9\r\n250 SIZE 10\r\n354 go");
+        assert_eq!(
+            codec.next_reply(),
+            Ok(Some("250-mx ét\u{fffd}\r\n250 SIZE 10\r\n".into()))
+        );
+        assert_eq!(codec.next_reply(), Ok(None));
+        codec.extend(b"\r\n");
+        assert_eq!(codec.next_reply(), Ok(Some("354 go\r\n".into())));
+        assert!(codec.is_empty());
+    }
+
+    #[test]
+    fn test_rejects_bad_replies() {
+        let mut codec = ReplyCodec::default();
+        codec.extend(b"250-first\r\n251 second\r\n");
+        assert!(codec.next_reply().unwrap_err().contains("250 then 251"));
+
+        let mut codec = ReplyCodec::new(16);
+        codec.extend(b"250-aaaaaaaaaaaaaaaaaaaa");
+        assert!(codec.next_reply().is_err());
+        assert!(codec.is_empty());
+
+        let mut codec = ReplyCodec::default();
+        codec.extend(b"250-first\r\n");
+        assert!(codec.finish().is_err());
+    }
+}
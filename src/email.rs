@@ -1,7 +1,10 @@
 //! Email building and MIME encoding (mirror of TS email module).
 
 use crate::errors::{InvalidContentError, InvalidEmailError};
-use crate::utils::{encode_header, encode_quoted_printable, is_valid_email};
+use crate::utils::{
+    encode_header, encode_quoted_printable, format_rfc2822_date, is_valid_email, now_millis,
+};
+use std::borrow::Cow;
 use std::collections::HashMap;
 
 /// Single recipient/sender with optional display name.
@@ -71,6 +74,94 @@ pub struct EmailOptions {
     pub headers: Option<HashMap<String, String>>,
     pub attachments: Option<Vec<Attachment>>,
     pub dsn_override: Option<DsnOverride>,
+    /// Release the message at this Unix time in milliseconds (RFC 4865 `HOLDUNTIL`).
+    pub hold_until: Option<u64>,
+    /// Release the message this many seconds after it is accepted (`HOLDFOR`).
+    pub hold_for: Option<u64>,
+    /// What to do when the server does not offer FUTURERELEASE.
+    #[serde(default)]
+    pub hold_fallback: HoldFallback,
+    /// RFC 6710 MT-PRIORITY, -9 (lowest) to 9 (highest); sent only when advertised.
+    pub priority: Option<i8>,
+    /// RFC 2852 DELIVERBY deadline; sent only when advertised.
+    pub deliver_by: Option<DeliverBy>,
+}
+
+/// Addresses and subject of a message: what the error and async send hooks get, so
+/// they don't need a copy of the bodies and attachments.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct EmailSummary {
+    pub from: String,
+    pub to: Vec<String>,
+    pub cc: Vec<String>,
+    pub bcc: Vec<String>,
+    pub subject: String,
+}
+
+impl From<&EmailOptions> for EmailSummary {
+    fn from(options: &EmailOptions) -> Self {
+        let addresses = |list: Option<&Vec<Recipient>>| {
+            list.into_iter()
+                .flatten()
+                .map(|r| r.email().to_string())
+                .collect()
+        };
+        Self {
+            from: options.from.email().to_string(),
+            to: addresses(Some(&options.to)),
+            cc: addresses(options.cc.as_ref()),
+            bcc: addresses(options.bcc.as_ref()),
+            subject: options.subject.clone(),
+        }
+    }
+}
+
+/// Deliver within `seconds` of the server accepting the message (`BY=seconds;R|N`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct DeliverBy {
+    /// Must be positive with `DeliverByMode::Return`.
+    pub seconds: i64,
+    pub mode: DeliverByMode,
+    /// Ask for a trace DSN of the relay path (`T`).
+    #[serde(default)]
+    pub trace: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DeliverByMode {
+    /// Return the message as undeliverable once the deadline passes (`R`).
+    Return,
+    /// Keep trying, but send a delay DSN once the deadline passes (`N`).
+    Notify,
+}
+
+impl std::fmt::Display for DeliverBy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mode = match self.mode {
+            DeliverByMode::Return => "R",
+            DeliverByMode::Notify => "N",
+        };
+        write!(
+            f,
+            "{};{}{}",
+            self.seconds,
+            mode,
+            if self.trace { "T" } else { "" }
+        )
+    }
+}
+
+/// What to do with a held message (`hold_until` / `hold_for`) when the server does not
+/// advertise FUTURERELEASE.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum HoldFallback {
+    /// Fail with `MailerError::Unsupported`, e.g. to schedule it another way.
+    #[default]
+    Fail,
+    /// Deliver it right away.
+    SendNow,
 }
 
 impl Default for EmailOptions {
@@ -87,6 +178,11 @@ impl Default for EmailOptions {
             headers: None,
             attachments: None,
             dsn_override: None,
+            hold_until: None,
+            hold_for: None,
+            hold_fallback: HoldFallback::Fail,
+            priority: None,
+            deliver_by: None,
         }
     }
 }
@@ -116,6 +212,16 @@ impl std::fmt::Display for EmailBuildError {
 
 impl std::error::Error for EmailBuildError {}
 
+impl Recipient {
+    /// The bare address.
+    pub fn email(&self) -> &str {
+        match self {
+            Recipient::Email(e) => e,
+            Recipient::User(u) => &u.email,
+        }
+    }
+}
+
 impl From<String> for Recipient {
     fn from(s: String) -> Self {
         Recipient::Email(s)
@@ -157,6 +263,11 @@ pub struct Email {
     pub attachments: Option<Vec<Attachment>>,
     pub dsn_override: Option<DsnOverride>,
     pub headers: HashMap<String, String>,
+    pub hold_until: Option<u64>,
+    pub hold_for: Option<u64>,
+    pub hold_fallback: HoldFallback,
+    pub priority: Option<i8>,
+    pub deliver_by: Option<DeliverBy>,
 }
 
 impl Email {
@@ -166,12 +277,30 @@ impl Email {
                 "At least one of text or html must be provided".to_string(),
             )));
         }
+        if options.hold_until.is_some() && options.hold_for.is_some() {
+            return Err(EmailBuildError::InvalidContent(InvalidContentError(
+                "hold_until and hold_for cannot both be set".to_string(),
+            )));
+        }
+        if options.priority.is_some_and(|p| !(-9..=9).contains(&p)) {
+            return Err(EmailBuildError::InvalidContent(InvalidContentError(
+                "priority must be between -9 and 9".to_string(),
+            )));
+        }
+        if options
+            .deliver_by
+            .is_some_and(|by| by.mode == DeliverByMode::Return && by.seconds <= 0)
+        {
+            return Err(EmailBuildError::InvalidContent(InvalidContentError(
+                "deliver_by must be positive when the message is returned".to_string(),
+            )));
+        }
 
         let from = one_recipient_to_user(&options.from);
         let to = recipients_to_users(&options.to);
         let reply = options.reply.map(|r| one_recipient_to_user(&r));
-    let cc = options.cc.as_deref().map(recipients_to_users);
-    let bcc = options.bcc.as_deref().map(recipients_to_users);
+        let cc = options.cc.as_deref().map(recipients_to_users);
+        let bcc = options.bcc.as_deref().map(recipients_to_users);
 
         let mut invalid = Vec::new();
         if !is_valid_email(&from.email) {
@@ -215,9 +344,42 @@ impl Email {
             attachments: options.attachments,
             dsn_override: options.dsn_override,
             headers,
+            hold_until: options.hold_until,
+            hold_for: options.hold_for,
+            hold_fallback: options.hold_fallback,
+            priority: options.priority,
+            deliver_by: options.deliver_by,
         })
     }
 
+    /// Like [`new`](Self::new), moving the bodies and attachments out of `options`
+    /// instead of copying them. They go back when the returned guard is dropped.
+    pub fn lend(options: &mut EmailOptions) -> Result<LentEmail<'_>, EmailBuildError> {
+        let light = EmailOptions {
+            from: options.from.clone(),
+            to: options.to.clone(),
+            reply: options.reply.clone(),
+            cc: options.cc.clone(),
+            bcc: options.bcc.clone(),
+            subject: options.subject.clone(),
+            text: options.text.as_ref().map(|_| String::new()),
+            html: options.html.as_ref().map(|_| String::new()),
+            headers: options.headers.clone(),
+            attachments: None,
+            dsn_override: options.dsn_override.clone(),
+            hold_until: options.hold_until,
+            hold_for: options.hold_for,
+            hold_fallback: options.hold_fallback,
+            priority: options.priority,
+            deliver_by: options.deliver_by,
+        };
+        let mut email = Self::new(light)?;
+        email.text = options.text.take();
+        email.html = options.html.take();
+        email.attachments = options.attachments.take();
+        Ok(LentEmail { email, options })
+    }
+
     fn generate_safe_boundary(prefix: &str) -> String {
         let mut bytes = [0u8; 28];
         getrandom::getrandom(&mut bytes).unwrap_or_default();
@@ -321,8 +483,8 @@ impl Email {
             }
         }
         if !self.headers.contains_key("Date") {
-            let now = worker::Date::now();
-            self.headers.insert("Date".to_string(), now.to_string());
+            self.headers
+                .insert("Date".to_string(), format_rfc2822_date(now_millis()));
         }
         if !self.headers.contains_key("Message-ID") {
             let id = uuid::Uuid::new_v4();
@@ -332,16 +494,36 @@ impl Email {
         }
     }
 
-    fn apply_dot_stuffing(data: &str) -> String {
-        let mut result = data.replace("\r\n.", "\r\n..");
-        if result.starts_with('.') {
-            result.insert(0, '.');
-        }
-        result
+    /// `Message-ID` header, once headers are resolved by [`get_email_data`](Self::get_email_data).
+    pub fn message_id(&self) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case("Message-ID"))
+            .map(|(_, v)| v.as_str())
     }
 
     /// Build raw MIME message (including final CRLF.CRLF).
     pub fn get_email_data(&mut self) -> String {
+        let mut data = String::new();
+        let mut stuffer = DotStuffer::default();
+        for chunk in self.chunks() {
+            stuffer.stuff(&chunk, &mut data);
+        }
+        data.push_str(DATA_END);
+        data
+    }
+
+    /// The MIME message in pieces that each end at a line boundary, before dot-stuffing
+    /// and without the final `.`. Attachments are wrapped lazily, a few KiB at a time, so
+    /// the whole message never has to sit in memory at once.
+    pub fn chunks(&mut self) -> MessageChunks<'_> {
+        MessageChunks {
+            segments: self.segments().into_iter(),
+            base64: "",
+        }
+    }
+
+    fn segments(&mut self) -> Vec<Segment<'_>> {
         self.resolve_headers();
 
         let mut headers_vec = vec!["MIME-Version: 1.0".to_string()];
@@ -363,6 +545,7 @@ impl Email {
         ));
         let headers = headers_vec.join("\r\n");
 
+        let mut segments = Vec::new();
         let mut email_data = format!("{}\r\n\r\n", headers);
         email_data.push_str(&format!("--{}\r\n", mixed_boundary));
 
@@ -378,47 +561,51 @@ impl Email {
             "Content-Type: multipart/alternative; boundary=\"{}\"\r\n\r\n",
             alternative_boundary
         ));
+        segments.push(Segment::Text(email_data));
 
         if let Some(ref text) = self.text {
-            email_data.push_str(&format!("--{}\r\n", alternative_boundary));
-            email_data.push_str("Content-Type: text/plain; charset=\"UTF-8\"\r\n");
-            email_data.push_str("Content-Transfer-Encoding: quoted-printable\r\n\r\n");
-            email_data.push_str(&encode_quoted_printable(text, 76));
-            email_data.push_str("\r\n\r\n");
+            let mut part = format!("--{}\r\n", alternative_boundary);
+            part.push_str("Content-Type: text/plain; charset=\"UTF-8\"\r\n");
+            part.push_str("Content-Transfer-Encoding: quoted-printable\r\n\r\n");
+            part.push_str(&encode_quoted_printable(text, 76));
+            part.push_str("\r\n\r\n");
+            segments.push(Segment::Text(part));
         }
         if let Some(ref html) = self.html {
-            email_data.push_str(&format!("--{}\r\n", alternative_boundary));
-            email_data.push_str("Content-Type: text/html; charset=\"UTF-8\"\r\n");
-            email_data.push_str("Content-Transfer-Encoding: quoted-printable\r\n\r\n");
-            email_data.push_str(&encode_quoted_printable(html, 76));
-            email_data.push_str("\r\n\r\n");
+            let mut part = format!("--{}\r\n", alternative_boundary);
+            part.push_str("Content-Type: text/html; charset=\"UTF-8\"\r\n");
+            part.push_str("Content-Transfer-Encoding: quoted-printable\r\n\r\n");
+            part.push_str(&encode_quoted_printable(html, 76));
+            part.push_str("\r\n\r\n");
+            segments.push(Segment::Text(part));
         }
-        email_data.push_str(&format!("--{}--\r\n", alternative_boundary));
+        segments.push(Segment::Text(format!("--{}--\r\n", alternative_boundary)));
 
         for att in &inline_attachments {
             let mime = att
                 .mime_type
                 .as_deref()
                 .unwrap_or_else(|| Self::get_mime_type(&att.filename));
-            email_data.push_str(&format!("--{}\r\n", related_boundary));
-            email_data.push_str(&format!(
+            let mut part = format!("--{}\r\n", related_boundary);
+            part.push_str(&format!(
                 "Content-Type: {}; name=\"{}\"\r\n",
                 mime, att.filename
             ));
-            email_data.push_str("Content-Transfer-Encoding: base64\r\n");
-            email_data.push_str(&format!("Content-ID: <{}>\r\n", att.cid.as_deref().unwrap_or("")));
-            email_data.push_str(&format!(
+            part.push_str("Content-Transfer-Encoding: base64\r\n");
+            part.push_str(&format!(
+                "Content-ID: <{}>\r\n",
+                att.cid.as_deref().unwrap_or("")
+            ));
+            part.push_str(&format!(
                 "Content-Disposition: inline; filename=\"{}\"\r\n\r\n",
                 att.filename
             ));
-            for chunk in att.content.as_bytes().chunks(72) {
-                email_data.push_str(std::str::from_utf8(chunk).unwrap_or(""));
-                email_data.push_str("\r\n");
-            }
-            email_data.push_str("\r\n");
+            segments.push(Segment::Text(part));
+            segments.push(Segment::Base64(&att.content));
+            segments.push(Segment::Text("\r\n".to_string()));
         }
         if !inline_attachments.is_empty() {
-            email_data.push_str(&format!("--{}--\r\n", related_boundary));
+            segments.push(Segment::Text(format!("--{}--\r\n", related_boundary)));
         }
 
         for att in &regular_attachments {
@@ -426,30 +613,192 @@ impl Email {
                 .mime_type
                 .as_deref()
                 .unwrap_or_else(|| Self::get_mime_type(&att.filename));
-            email_data.push_str(&format!("--{}\r\n", mixed_boundary));
-            email_data.push_str(&format!(
+            let mut part = format!("--{}\r\n", mixed_boundary);
+            part.push_str(&format!(
                 "Content-Type: {}; name=\"{}\"\r\n",
                 mime, att.filename
             ));
-            email_data.push_str(&format!("Content-Description: {}\r\n", att.filename));
-            email_data.push_str(&format!(
+            part.push_str(&format!("Content-Description: {}\r\n", att.filename));
+            part.push_str(&format!(
                 "Content-Disposition: attachment; filename=\"{}\";\r\n",
                 att.filename
             ));
-            let now = worker::Date::now();
-            email_data.push_str(&format!("    creation-date=\"{}\";\r\n", now.to_string()));
-            email_data.push_str("Content-Transfer-Encoding: base64\r\n\r\n");
-            for chunk in att.content.as_bytes().chunks(72) {
-                email_data.push_str(std::str::from_utf8(chunk).unwrap_or(""));
-                email_data.push_str("\r\n");
+            part.push_str(&format!(
+                "    creation-date=\"{}\";\r\n",
+                format_rfc2822_date(now_millis())
+            ));
+            part.push_str("Content-Transfer-Encoding: base64\r\n\r\n");
+            segments.push(Segment::Text(part));
+            segments.push(Segment::Base64(&att.content));
+            segments.push(Segment::Text("\r\n".to_string()));
+        }
+
+        segments.push(Segment::Text(format!("--{}--\r\n", mixed_boundary)));
+        segments
+    }
+}
+
+/// [`Email`] returned by [`Email::lend`]; hands the bodies and attachments back to the
+/// options on drop.
+pub struct LentEmail<'a> {
+    email: Email,
+    options: &'a mut EmailOptions,
+}
+
+impl std::ops::Deref for LentEmail<'_> {
+    type Target = Email;
+
+    fn deref(&self) -> &Email {
+        &self.email
+    }
+}
+
+impl std::ops::DerefMut for LentEmail<'_> {
+    fn deref_mut(&mut self) -> &mut Email {
+        &mut self.email
+    }
+}
+
+impl Drop for LentEmail<'_> {
+    fn drop(&mut self) {
+        self.options.text = self.email.text.take();
+        self.options.html = self.email.html.take();
+        self.options.attachments = self.email.attachments.take();
+    }
+}
+
+/// Ends the DATA body (RFC 5321 section 4.1.1.4). The message already ends with CRLF,
+/// so only the `.` line is added.
+pub const DATA_END: &str = ".\r\n";
+
+/// Base64 line length in attachments, and lines wrapped per chunk.
+const BASE64_LINE: usize = 72;
+const BASE64_LINES_PER_CHUNK: usize = 64;
+
+enum Segment<'a> {
+    Text(String),
+    /// Attachment content, wrapped into lines as it is streamed.
+    Base64(&'a str),
+}
+
+/// Iterator returned by [`Email::chunks`].
+pub struct MessageChunks<'a> {
+    segments: std::vec::IntoIter<Segment<'a>>,
+    /// Base64 still to wrap.
+    base64: &'a str,
+}
+
+impl<'a> Iterator for MessageChunks<'a> {
+    type Item = Cow<'a, str>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.base64.is_empty() {
+            match self.segments.next()? {
+                Segment::Text(text) => return Some(Cow::Owned(text)),
+                Segment::Base64(content) => self.base64 = content,
             }
-            email_data.push_str("\r\n");
         }
+        let budget = BASE64_LINE * BASE64_LINES_PER_CHUNK;
+        let mut chunk = String::with_capacity(budget + budget / BASE64_LINE * 2);
+        while !self.base64.is_empty() && chunk.len() < budget {
+            // Content is expected to be ASCII, but a stray multibyte character must not
+            // be cut in half (or dropped).
+            let mut end = self.base64.len().min(BASE64_LINE);
+            while !self.base64.is_char_boundary(end) {
+                end += 1;
+            }
+            let (line, rest) = self.base64.split_at(end);
+            chunk.push_str(line);
+            chunk.push_str("\r\n");
+            self.base64 = rest;
+        }
+        Some(Cow::Owned(chunk))
+    }
+}
+
+/// Doubles a `.` at the start of each line (RFC 5321 section 4.5.2), also when the line
+/// starts in a later chunk.
+#[derive(Debug)]
+pub struct DotStuffer {
+    line_start: bool,
+    after_cr: bool,
+}
 
-        email_data.push_str(&format!("--{}--\r\n", mixed_boundary));
+impl Default for DotStuffer {
+    fn default() -> Self {
+        Self {
+            line_start: true,
+            after_cr: false,
+        }
+    }
+}
 
-        let safe = Self::apply_dot_stuffing(&email_data);
-        format!("{}\r\n.\r\n", safe)
+impl DotStuffer {
+    /// Appends `chunk` to `out`, dot-stuffed.
+    pub fn stuff(&mut self, chunk: &str, out: &mut String) {
+        out.reserve(chunk.len());
+        for c in chunk.chars() {
+            if self.line_start && c == '.' {
+                out.push('.');
+            }
+            out.push(c);
+            self.line_start = self.after_cr && c == '\n';
+            self.after_cr = c == '\r';
+        }
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_dot_stuffing_across_chunks() {
+        let mut stuffer = DotStuffer::default();
+        let mut out = String::new();
+        for chunk in [".a\r", "\n.b\r\n", ".", "c.\r\nd"] {
+            stuffer.stuff(chunk, &mut out);
+        }
+        assert_eq!(out, "..a\r\n..b\r\n..c.\r\nd");
+    }
+
+    #[test]
+    fn test_chunks_wrap_attachments() {
+        let content = "QUJD".repeat(2_000);
+        let mut email = Email::new(EmailOptions {
+            from: Recipient::Email("from@example.com".into()),
+            to: vec![Recipient::Email("to@example.com".into())],
+            text: Some("Hi".into()),
+            attachments: Some(vec![Attachment {
+                filename: "a.bin".into(),
+                content: content.clone(),
+                mime_type: None,
+                cid: None,
+                inline: None,
+            }]),
+            ..Default::default()
+        })
+        .unwrap();
+        let chunks: Vec<_> = email.chunks().collect();
+        assert!(chunks
+            .iter()
+            .all(|c| c.ends_with("\r\n") && c.len() < 8 * 1024));
+        let wrapped: String = content
+            .as_bytes()
+            .chunks(BASE64_LINE)
+            .map(|l| format!("{}\r\n", std::str::from_utf8(l).unwrap()))
+            .collect();
+        assert!(chunks.concat().contains(&wrapped));
+
+        // a multibyte character never splits a line, nor loses the rest of the content
+        let content = format!("{}é{}", "A".repeat(71), "B".repeat(5_000));
+        let mut chunks = MessageChunks {
+            segments: vec![Segment::Base64(&content)].into_iter(),
+            base64: "",
+        };
+        let first = chunks.next().unwrap();
+        assert!(first.starts_with(&format!("{}é\r\n", "A".repeat(71))));
+        let rest: String = chunks.collect();
+        assert_eq!((first + rest.as_str()).replace("\r\n", ""), content);
+    }
+}
@@ -1,9 +1,11 @@
 //! Error types for WorkerMailer (mirror of TS module).
 
+use crate::email::EmailBuildError;
+use crate::reply::SmtpReply;
 use thiserror::Error;
 
 /// Base error for WorkerMailer.
-#[derive(Error, Debug)]
+#[derive(Error, Debug, Clone)]
 #[error("{message}")]
 pub struct WorkerMailerError {
     pub message: String,
@@ -11,7 +13,7 @@ pub struct WorkerMailerError {
 }
 
 /// Invalid email address(es).
-#[derive(Error, Debug)]
+#[derive(Error, Debug, Clone)]
 #[error("{message}")]
 pub struct InvalidEmailError {
     pub message: String,
@@ -29,7 +31,7 @@ impl InvalidEmailError {
 }
 
 /// SMTP authentication failed.
-#[derive(Error, Debug)]
+#[derive(Error, Debug, Clone)]
 #[error("{0}")]
 pub struct SmtpAuthError(pub String);
 
@@ -38,7 +40,7 @@ impl SmtpAuthError {
 }
 
 /// SMTP connection failed.
-#[derive(Error, Debug)]
+#[derive(Error, Debug, Clone)]
 #[error("{0}")]
 pub struct SmtpConnectionError(pub String);
 
@@ -47,11 +49,13 @@ impl SmtpConnectionError {
 }
 
 /// Recipient rejected by SMTP server.
-#[derive(Error, Debug)]
+#[derive(Error, Debug, Clone)]
 #[error("{message}")]
 pub struct SmtpRecipientError {
     pub message: String,
     pub recipient: String,
+    /// The server's reply to `RCPT TO`, when there was one.
+    pub reply: Option<SmtpReply>,
 }
 
 impl SmtpRecipientError {
@@ -60,24 +64,187 @@ impl SmtpRecipientError {
         Self {
             message: message.into(),
             recipient: recipient.into(),
+            reply: None,
+        }
+    }
+
+    pub fn with_reply(mut self, reply: SmtpReply) -> Self {
+        self.reply = Some(reply);
+        self
+    }
+}
+
+/// The server answered a command with an unexpected reply.
+#[derive(Error, Debug, Clone)]
+#[error("{message}: {reply}")]
+pub struct SmtpReplyError {
+    pub message: String,
+    pub reply: SmtpReply,
+}
+
+impl SmtpReplyError {
+    pub const CODE: &'static str = "UNEXPECTED_REPLY";
+    pub fn new(message: impl Into<String>, reply: SmtpReply) -> Self {
+        Self {
+            message: message.into(),
+            reply,
         }
     }
 }
 
+/// Step of the SMTP session, as reported by timeouts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SmtpPhase {
+    Connect,
+    Greeting,
+    Ehlo,
+    StartTls,
+    Auth,
+    MailFrom,
+    RcptTo,
+    Data,
+    Reset,
+    Noop,
+    Quit,
+}
+
+impl std::fmt::Display for SmtpPhase {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            SmtpPhase::Connect => "connect",
+            SmtpPhase::Greeting => "greeting",
+            SmtpPhase::Ehlo => "EHLO",
+            SmtpPhase::StartTls => "STARTTLS",
+            SmtpPhase::Auth => "AUTH",
+            SmtpPhase::MailFrom => "MAIL FROM",
+            SmtpPhase::RcptTo => "RCPT TO",
+            SmtpPhase::Data => "DATA",
+            SmtpPhase::Reset => "RSET",
+            SmtpPhase::Noop => "NOOP",
+            SmtpPhase::Quit => "QUIT",
+        })
+    }
+}
+
 /// SMTP operation timeout.
-#[derive(Error, Debug)]
-#[error("{0}")]
-pub struct SmtpTimeoutError(pub String);
+#[derive(Error, Debug, Clone)]
+#[error("{message}")]
+pub struct SmtpTimeoutError {
+    pub message: String,
+    /// Step that stalled.
+    pub phase: SmtpPhase,
+}
 
 impl SmtpTimeoutError {
     pub const CODE: &'static str = "TIMEOUT";
+    pub fn new(phase: SmtpPhase, timeout_ms: u64) -> Self {
+        Self {
+            message: format!("{} timed out after {} ms", phase, timeout_ms),
+            phase,
+        }
+    }
 }
 
 /// Invalid email content (e.g. missing text and html).
-#[derive(Error, Debug)]
+#[derive(Error, Debug, Clone)]
 #[error("{0}")]
 pub struct InvalidContentError(pub String);
 
 impl InvalidContentError {
     pub const CODE: &'static str = "INVALID_CONTENT";
 }
+
+/// Any failure of the SMTP client.
+#[derive(Error, Debug, Clone)]
+pub enum MailerError {
+    #[error(transparent)]
+    InvalidEmail(#[from] InvalidEmailError),
+    #[error(transparent)]
+    InvalidContent(#[from] InvalidContentError),
+    #[error(transparent)]
+    Auth(#[from] SmtpAuthError),
+    #[error(transparent)]
+    Connection(#[from] SmtpConnectionError),
+    #[error(transparent)]
+    Recipient(#[from] SmtpRecipientError),
+    #[error(transparent)]
+    Timeout(#[from] SmtpTimeoutError),
+    /// The server answered a command with an unexpected reply.
+    #[error(transparent)]
+    Reply(#[from] SmtpReplyError),
+    /// The server broke the protocol (malformed reply, invalid challenge).
+    #[error("Protocol error: {0}")]
+    Protocol(String),
+    /// TLS handshake or STARTTLS upgrade failed.
+    #[error("TLS error: {0}")]
+    Tls(String),
+    /// `WorkerMailerOptions` are invalid; nothing was sent.
+    #[error("Invalid configuration: {0}")]
+    Config(String),
+    /// A `before_send` hook refused the message; nothing was sent.
+    #[error("Send vetoed: {0}")]
+    Vetoed(String),
+    /// The message needs an extension the server lacks, or exceeds its limits.
+    #[error("Not supported by the server: {0}")]
+    Unsupported(String),
+    /// The connection failed or timed out after the message body was sent, so the server
+    /// may have accepted it. Not retried, to avoid sending it twice.
+    #[error("Delivery unconfirmed, the message may have been accepted: {0}")]
+    Unconfirmed(String),
+}
+
+impl MailerError {
+    /// Stable machine-readable code (the wrapped type's `CODE`).
+    pub fn code(&self) -> &'static str {
+        match self {
+            MailerError::InvalidEmail(_) => InvalidEmailError::CODE,
+            MailerError::InvalidContent(_) => InvalidContentError::CODE,
+            MailerError::Auth(_) => SmtpAuthError::CODE,
+            MailerError::Connection(_) => SmtpConnectionError::CODE,
+            MailerError::Recipient(_) => SmtpRecipientError::CODE,
+            MailerError::Timeout(_) => SmtpTimeoutError::CODE,
+            MailerError::Reply(_) => SmtpReplyError::CODE,
+            MailerError::Protocol(_) => "PROTOCOL_ERROR",
+            MailerError::Tls(_) => "TLS_FAILED",
+            MailerError::Config(_) => "INVALID_CONFIG",
+            MailerError::Vetoed(_) => "VETOED",
+            MailerError::Unsupported(_) => "UNSUPPORTED",
+            MailerError::Unconfirmed(_) => "UNCONFIRMED",
+        }
+    }
+
+    /// Whether the same send may succeed later: network failures, timeouts and 4xx replies.
+    pub fn retryable(&self) -> bool {
+        match self {
+            MailerError::Connection(_) | MailerError::Timeout(_) => true,
+            MailerError::Reply(e) => e.reply.is_transient(),
+            MailerError::Recipient(e) => e.reply.as_ref().is_some_and(SmtpReply::is_transient),
+            _ => false,
+        }
+    }
+
+    /// The server reply behind this error, if any.
+    pub fn reply(&self) -> Option<&SmtpReply> {
+        match self {
+            MailerError::Reply(e) => Some(&e.reply),
+            MailerError::Recipient(e) => e.reply.as_ref(),
+            _ => None,
+        }
+    }
+}
+
+impl From<EmailBuildError> for MailerError {
+    fn from(e: EmailBuildError) -> Self {
+        match e {
+            EmailBuildError::InvalidContent(e) => MailerError::InvalidContent(e),
+            EmailBuildError::InvalidEmail(e) => MailerError::InvalidEmail(e),
+        }
+    }
+}
+
+impl From<MailerError> for worker::Error {
+    fn from(e: MailerError) -> Self {
+        worker::Error::RustError(e.to_string())
+    }
+}
@@ -2,23 +2,46 @@
 //!
 //! Port of the TypeScript [@ribassu/worker-mailer](https://github.com/RibasSu/worker-mailer) for use in Cloudflare Workers with Rust.
 
+pub mod auth;
+pub mod capabilities;
+pub mod codec;
 pub mod email;
 pub mod errors;
 pub mod logger;
 pub mod mailer;
 pub mod queue;
+pub mod reply;
+pub mod retry;
+pub mod transcript;
+pub mod transport;
 pub mod utils;
 
 // Re-exports
-pub use email::{Attachment, DsnNotify, DsnOverride, DsnRet, Email, EmailBuildError, EmailOptions, Recipient, User};
+pub use auth::{OAuth2TokenEndpoint, TokenProvider};
+pub use capabilities::{FutureRelease, ServerCapabilities};
+pub use email::{
+    Attachment, DeliverBy, DeliverByMode, DsnNotify, DsnOverride, DsnRet, Email, EmailBuildError,
+    EmailOptions, EmailSummary, HoldFallback, Recipient, User,
+};
 pub use errors::{
-    InvalidContentError, InvalidEmailError, SmtpAuthError, SmtpConnectionError, SmtpRecipientError,
-    SmtpTimeoutError, WorkerMailerError,
+    InvalidContentError, InvalidEmailError, MailerError, SmtpAuthError, SmtpConnectionError,
+    SmtpPhase, SmtpRecipientError, SmtpReplyError, SmtpTimeoutError, WorkerMailerError,
 };
 pub use logger::{LogLevel, Logger};
 pub use mailer::{
-    AuthType, Credentials, DsnNotify as DsnNotifyOpt, DsnOptions, DsnRet as DsnRetOpt,
-    WorkerMailer, WorkerMailerHooks, WorkerMailerOptions,
+    AcceptedRecipient, AsyncHook, AuthType, Credentials, DsnNotify as DsnNotifyOpt, DsnOptions,
+    DsnRet as DsnRetOpt, Endpoint, RecipientPolicy, RecipientVerdict, RecipientVerification,
+    SendResult, SendTimings, TlsPolicy, WorkerMailer, WorkerMailerHooks, WorkerMailerOptions,
+};
+pub use queue::{
+    enqueue_email, enqueue_emails, process_batch, QueueEmailMessage, QueueProcessResult,
 };
-pub use queue::{enqueue_email, enqueue_emails, process_batch, QueueEmailMessage, QueueProcessResult};
+pub use reply::{EnhancedStatus, SmtpReply};
+pub use retry::RetryPolicy;
+pub use transcript::{Direction, Transcript, TranscriptLine, TranscriptOptions};
+pub use transport::{CloudflareConnector, CloudflareTransport, Connector, Transport};
+#[cfg(any(test, feature = "testing"))]
+pub use transport::{MemoryConnector, MemoryTransport};
+#[cfg(all(feature = "native", not(target_arch = "wasm32")))]
+pub use transport::{TokioConnector, TokioTransport};
 pub use utils::{decode, encode_header, encode_quoted_printable, is_valid_email, validate_emails};
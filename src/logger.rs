@@ -1,6 +1,17 @@
 //! Simple logger with log levels (mirror of TS logger).
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default, serde::Serialize, serde::Deserialize)]
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    Default,
+    serde::Serialize,
+    serde::Deserialize,
+)]
 #[serde(rename_all = "lowercase")]
 pub enum LogLevel {
     #[default]
@@ -11,6 +22,19 @@ pub enum LogLevel {
     None = 4,
 }
 
+// `console_*` calls only work inside workerd; native builds log to stderr.
+#[cfg(target_arch = "wasm32")]
+macro_rules! emit {
+    (log, $($t:tt)*) => { worker::console_log!($($t)*) };
+    (warn, $($t:tt)*) => { worker::console_warn!($($t)*) };
+    (error, $($t:tt)*) => { worker::console_error!($($t)*) };
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+macro_rules! emit {
+    ($kind:ident, $($t:tt)*) => { eprintln!($($t)*) };
+}
+
 pub struct Logger {
     level: LogLevel,
     prefix: String,
@@ -24,27 +48,32 @@ impl Logger {
         }
     }
 
+    /// Whether messages at `level` are printed.
+    pub fn enabled(&self, level: LogLevel) -> bool {
+        self.level <= level
+    }
+
     pub fn debug(&self, message: &str) {
         if self.level <= LogLevel::Debug {
-            worker::console_log!("{}", format!("{}{}", self.prefix, message));
+            emit!(log, "{}", format!("{}{}", self.prefix, message));
         }
     }
 
     pub fn info(&self, message: &str) {
         if self.level <= LogLevel::Info {
-            worker::console_log!("{}", format!("{}{}", self.prefix, message));
+            emit!(log, "{}", format!("{}{}", self.prefix, message));
         }
     }
 
     pub fn warn(&self, message: &str) {
         if self.level <= LogLevel::Warn {
-            worker::console_warn!("{}", format!("{}{}", self.prefix, message));
+            emit!(warn, "{}", format!("{}{}", self.prefix, message));
         }
     }
 
     pub fn error(&self, message: &str) {
         if self.level <= LogLevel::Error {
-            worker::console_error!("{}", format!("{}{}", self.prefix, message));
+            emit!(error, "{}", format!("{}{}", self.prefix, message));
         }
     }
 }
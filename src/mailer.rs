@@ -1,12 +1,32 @@
 //! SMTP client for Cloudflare Workers (mirror of TS mailer).
 
-use crate::email::{Email, EmailOptions};
+use crate::auth::{
+    cram_md5_response, oauth_error_message, oauthbearer_initial_response, xoauth2_initial_response,
+    ScramClient, ScramHash, TokenProvider,
+};
+use crate::capabilities::ServerCapabilities;
+use crate::codec::ReplyCodec;
+use crate::email::{
+    DeliverByMode, DotStuffer, DsnOverride, Email, EmailOptions, EmailSummary, HoldFallback,
+    DATA_END,
+};
+use crate::errors::{
+    InvalidEmailError, MailerError, SmtpAuthError, SmtpConnectionError, SmtpPhase,
+    SmtpRecipientError, SmtpReplyError, SmtpTimeoutError,
+};
 use crate::logger::{LogLevel, Logger};
-use crate::utils::{decode, encode};
-use worker::ConnectionBuilder;
-use worker::Socket;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use crate::reply::SmtpReply;
+use crate::retry::RetryPolicy;
+use crate::transcript::{redact_auth, Direction, Transcript, TranscriptOptions};
+use crate::transport::{default_connector, Connector, Transport};
+use crate::utils::{
+    encode, encode_xtext, format_rfc3339, is_valid_email, normalize_client_hostname, now_millis,
+    random_u64, sleep, timeout,
+};
 use base64::{engine::general_purpose::STANDARD as B64, Engine};
+use std::future::Future;
+use std::pin::Pin;
+use std::rc::Rc;
 
 /// Auth methods supported by the client.
 #[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
@@ -15,30 +35,213 @@ pub enum AuthType {
     Plain,
     Login,
     CramMd5,
+    /// Google/Microsoft `XOAUTH2`.
+    XOAuth2,
+    /// RFC 7628 `OAUTHBEARER`.
+    OAuthBearer,
+    ScramSha1,
+    ScramSha256,
 }
 
-/// SMTP credentials.
+impl AuthType {
+    /// SASL mechanism name as advertised in EHLO.
+    pub fn mechanism(self) -> &'static str {
+        match self {
+            AuthType::Plain => "PLAIN",
+            AuthType::Login => "LOGIN",
+            AuthType::CramMd5 => "CRAM-MD5",
+            AuthType::XOAuth2 => "XOAUTH2",
+            AuthType::OAuthBearer => "OAUTHBEARER",
+            AuthType::ScramSha1 => "SCRAM-SHA-1",
+            AuthType::ScramSha256 => "SCRAM-SHA-256",
+        }
+    }
+}
+
+/// How the connection is protected.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TlsPolicy {
+    /// TLS from the first byte (SMTPS, port 465).
+    Implicit,
+    /// Upgrade with STARTTLS; fail if the server does not offer it.
+    StartTlsRequired,
+    /// Upgrade with STARTTLS when offered, else stay in plaintext.
+    StartTlsOpportunistic,
+    /// Never use TLS.
+    None,
+}
+
+/// One relay in a failover list. Lower `priority` is tried first; relays sharing a
+/// priority are ordered at random in proportion to `weight`, as with DNS SRV records.
 #[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
-pub struct Credentials {
-    pub username: String,
-    pub password: String,
+pub struct Endpoint {
+    pub host: String,
+    pub port: u16,
+    #[serde(default)]
+    pub priority: u32,
+    #[serde(default = "default_weight")]
+    pub weight: u32,
+    /// Overrides `WorkerMailerOptions::credentials` for this relay.
+    #[serde(default)]
+    pub credentials: Option<Credentials>,
+    /// Overrides the mailer's TLS policy for this relay.
+    #[serde(default)]
+    pub tls: Option<TlsPolicy>,
 }
 
-/// Hooks for mailer events (not serialized for queue).
-#[derive(Default)]
-pub struct WorkerMailerHooks {
-    pub on_connect: Option<Box<dyn Fn()>>,
-    pub on_sent: Option<Box<dyn Fn(&EmailOptions, &str)>>,
-    pub on_error: Option<Box<dyn Fn(Option<&EmailOptions>, &dyn std::error::Error)>>,
-    pub on_close: Option<Box<dyn Fn(Option<&worker::Error>)>>,
+impl Endpoint {
+    pub fn new(host: impl Into<String>, port: u16) -> Self {
+        Self {
+            host: host.into(),
+            port,
+            priority: 0,
+            weight: default_weight(),
+            credentials: None,
+            tls: None,
+        }
+    }
+}
+
+fn default_weight() -> u32 {
+    1
+}
+
+/// Endpoint indices by ascending priority, each priority group shuffled by weight.
+fn failover_order(endpoints: &[Endpoint]) -> Vec<usize> {
+    let mut remaining: Vec<usize> = (0..endpoints.len()).collect();
+    remaining.sort_by_key(|&i| endpoints[i].priority);
+    let mut order = Vec::with_capacity(remaining.len());
+    while let Some(&first) = remaining.first() {
+        let priority = endpoints[first].priority;
+        let len = remaining
+            .iter()
+            .take_while(|&&i| endpoints[i].priority == priority)
+            .count();
+        let mut group: Vec<usize> = remaining.drain(..len).collect();
+        while !group.is_empty() {
+            let total: u64 = group.iter().map(|&i| u64::from(endpoints[i].weight)).sum();
+            let mut pick = 0;
+            if total > 0 {
+                let mut r = random_u64() % total;
+                for (n, &i) in group.iter().enumerate() {
+                    let weight = u64::from(endpoints[i].weight);
+                    if r < weight {
+                        pick = n;
+                        break;
+                    }
+                    r -= weight;
+                }
+            }
+            order.push(group.remove(pick));
+        }
+    }
+    order
+}
+
+/// SMTP credentials: a password for PLAIN/LOGIN/CRAM-MD5, or an OAuth 2.0 access
+/// token for XOAUTH2/OAUTHBEARER.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[serde(untagged)]
+pub enum Credentials {
+    Password {
+        username: String,
+        password: String,
+    },
+    OAuth2 {
+        username: String,
+        access_token: String,
+    },
+}
+
+impl Credentials {
+    pub fn password(username: impl Into<String>, password: impl Into<String>) -> Self {
+        Credentials::Password {
+            username: username.into(),
+            password: password.into(),
+        }
+    }
+
+    /// OAuth 2.0 credentials. `access_token` may be empty when a token provider is set.
+    pub fn oauth2(username: impl Into<String>, access_token: impl Into<String>) -> Self {
+        Credentials::OAuth2 {
+            username: username.into(),
+            access_token: access_token.into(),
+        }
+    }
+
+    pub fn username(&self) -> &str {
+        match self {
+            Credentials::Password { username, .. } | Credentials::OAuth2 { username, .. } => {
+                username
+            }
+        }
+    }
+}
+
+pub type OnConnectHook = Rc<dyn Fn()>;
+pub type OnSentHook = Rc<dyn Fn(&EmailOptions, &SendResult)>;
+pub type OnErrorHook = Rc<dyn Fn(Option<&EmailSummary>, &MailerError)>;
+pub type OnCloseHook = Rc<dyn Fn(Option<&MailerError>)>;
+/// May edit the message, or return `Err(reason)` to cancel it.
+pub type BeforeSendHook = Rc<dyn Fn(&mut EmailOptions) -> Result<(), String>>;
+pub type OnRecipientRejectedHook = Rc<dyn Fn(&SmtpRecipientError)>;
+/// Gets the relay of the new session.
+pub type OnReconnectHook = Rc<dyn Fn(&str)>;
+
+pub type HookFuture<R> = Pin<Box<dyn Future<Output = R>>>;
+
+/// Async hook. It gets owned arguments, so its future may outlive the event (e.g. a
+/// write to KV or D1); the mailer awaits it before going on. Messages are passed as an
+/// [`EmailSummary`], except to `before_send_async`, which gets the message itself.
+pub struct AsyncHook<T, R = ()>(Rc<dyn Fn(T) -> HookFuture<R>>);
+
+impl<T, R> AsyncHook<T, R> {
+    pub fn new<F, Fut>(f: F) -> Self
+    where
+        F: Fn(T) -> Fut + 'static,
+        Fut: Future<Output = R> + 'static,
+    {
+        Self(Rc::new(move |arg| Box::pin(f(arg))))
+    }
+
+    pub async fn call(&self, arg: T) -> R {
+        (self.0)(arg).await
+    }
 }
 
-impl Clone for WorkerMailerHooks {
+impl<T, R> Clone for AsyncHook<T, R> {
     fn clone(&self) -> Self {
-        Self::default()
+        Self(self.0.clone())
     }
 }
 
+/// Hooks for mailer events (not serialized for queue). Each `_async` variant runs after
+/// its sync counterpart.
+#[derive(Default, Clone)]
+pub struct WorkerMailerHooks {
+    /// Every new session, reconnects included.
+    pub on_connect: Option<OnConnectHook>,
+    pub on_sent: Option<OnSentHook>,
+    /// Every failed connect, send attempt, verification, `reset`, `noop` or close, with the
+    /// email when there is one.
+    pub on_error: Option<OnErrorHook>,
+    /// Gets the error passed to `close`.
+    pub on_close: Option<OnCloseHook>,
+    pub before_send: Option<BeforeSendHook>,
+    pub on_recipient_rejected: Option<OnRecipientRejectedHook>,
+    /// A session after the first one (lost session, message limit, retry).
+    pub on_reconnect: Option<OnReconnectHook>,
+    pub on_connect_async: Option<AsyncHook<()>>,
+    pub on_sent_async: Option<AsyncHook<(EmailSummary, SendResult)>>,
+    pub on_error_async: Option<AsyncHook<(Option<EmailSummary>, MailerError)>>,
+    pub on_close_async: Option<AsyncHook<Option<MailerError>>>,
+    /// Returns the message to send, or `Err(reason)` to cancel it.
+    pub before_send_async: Option<AsyncHook<EmailOptions, Result<EmailOptions, String>>>,
+    pub on_recipient_rejected_async: Option<AsyncHook<SmtpRecipientError>>,
+    pub on_reconnect_async: Option<AsyncHook<String>>,
+}
+
 impl std::fmt::Debug for WorkerMailerHooks {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.write_str("WorkerMailerHooks")
@@ -65,27 +268,283 @@ pub struct DsnNotify {
     pub success: Option<bool>,
 }
 
+/// RFC 3461 parameters for one message: the per-message override wins field by field.
+struct DsnParams {
+    ret: Option<&'static str>,
+    envelope_id: Option<String>,
+    notify: Option<String>,
+}
+
+impl DsnParams {
+    fn resolve(global: Option<&DsnOptions>, over: Option<&DsnOverride>) -> Self {
+        let ret = match over.and_then(|o| o.ret.as_ref()) {
+            Some(r) => ret_keyword(r.full, r.headers),
+            None => global
+                .and_then(|g| g.ret.as_ref())
+                .and_then(|r| ret_keyword(r.full, r.headers)),
+        };
+        let notify = match over.and_then(|o| o.notify.as_ref()) {
+            Some(n) => Some(notify_keyword(n.success, n.failure, n.delay)),
+            None => global
+                .and_then(|g| g.notify.as_ref())
+                .map(|n| notify_keyword(n.success, n.failure, n.delay)),
+        };
+        Self {
+            ret,
+            envelope_id: over.and_then(|o| o.envelope_id.clone()),
+            notify,
+        }
+    }
+}
+
+fn ret_keyword(full: Option<bool>, headers: Option<bool>) -> Option<&'static str> {
+    if full == Some(true) {
+        Some("FULL")
+    } else if headers == Some(true) {
+        Some("HDRS")
+    } else {
+        None
+    }
+}
+
+/// `SUCCESS,FAILURE,DELAY` subset, or `NEVER` when no condition is enabled.
+fn notify_keyword(success: Option<bool>, failure: Option<bool>, delay: Option<bool>) -> String {
+    let conditions: Vec<&str> = [(success, "SUCCESS"), (failure, "FAILURE"), (delay, "DELAY")]
+        .into_iter()
+        .filter(|(on, _)| *on == Some(true))
+        .map(|(_, name)| name)
+        .collect();
+    if conditions.is_empty() {
+        "NEVER".to_string()
+    } else {
+        conditions.join(",")
+    }
+}
+
+/// Body bytes gathered before each socket write.
+const BODY_WRITE_SIZE: usize = 16 * 1024;
+
+fn reply_error(message: impl Into<String>, reply: SmtpReply) -> MailerError {
+    SmtpReplyError::new(message, reply).into()
+}
+
+/// A 4xx reply to AUTH is a temporary server condition, anything else rejects the credentials.
+fn auth_error(message: impl Into<String>, reply: SmtpReply) -> MailerError {
+    if reply.is_transient() {
+        return reply_error(message, reply);
+    }
+    SmtpAuthError(format!("{}: {}", message.into(), reply)).into()
+}
+
+fn rejection(address: &str, reply: SmtpReply) -> SmtpRecipientError {
+    SmtpRecipientError::new(
+        format!("RCPT TO failed for {}: {}", address, reply),
+        address,
+    )
+    .with_reply(reply)
+}
+
+/// EHLO identity from the domain of an address, when that domain is a valid FQDN.
+fn domain_identity(address: &str) -> Option<String> {
+    let (_, domain) = address.rsplit_once('@')?;
+    normalize_client_hostname(domain).filter(|d| !d.starts_with('['))
+}
+
+/// The server ended the session (421), refuses more mail on it (4.7.0), or the
+/// connection is gone.
+fn session_lost(error: &MailerError) -> bool {
+    matches!(error, MailerError::Connection(_))
+        || error
+            .reply()
+            .is_some_and(|r| r.code == 421 || (r.is_transient() && r.has_status("4.7.0")))
+}
+
+/// Failures that only concern the relay being tried, so the next one is worth a go.
+fn relay_unavailable(error: &MailerError, phase: SmtpPhase) -> bool {
+    match error {
+        MailerError::Connection(_) | MailerError::Tls(_) | MailerError::Timeout(_) => true,
+        MailerError::Reply(e) => phase == SmtpPhase::Greeting && e.reply.is_transient(),
+        _ => false,
+    }
+}
+
+/// `error` for a message whose body went out: without a final reply, the server may
+/// have accepted it.
+fn unconfirmed(error: MailerError) -> MailerError {
+    match error {
+        MailerError::Connection(_) | MailerError::Timeout(_) => {
+            MailerError::Unconfirmed(error.to_string())
+        }
+        error => error,
+    }
+}
+
+fn connection_error(message: impl Into<String>) -> MailerError {
+    SmtpConnectionError(message.into()).into()
+}
+
+/// What to do when the server rejects some recipients of a message.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RecipientPolicy {
+    /// Any rejection fails the message.
+    #[default]
+    FailAll,
+    /// Deliver to the accepted recipients; fail only when every recipient is rejected.
+    ContinueIfAnyAccepted,
+}
+
+/// A recipient the server accepted.
+#[derive(Debug, Clone)]
+pub struct AcceptedRecipient {
+    pub recipient: String,
+    /// Reply to `RCPT TO`.
+    pub reply: SmtpReply,
+}
+
+/// Verdict of [`WorkerMailer::verify_recipients`] on one address.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RecipientVerdict {
+    /// 2xx to `RCPT TO`.
+    Accepted,
+    /// 5xx, or not a valid address.
+    Rejected,
+    /// 4xx, or not asked because the server limited the rate or closed the session.
+    Deferred,
+}
+
+#[derive(Debug, Clone)]
+pub struct RecipientVerification {
+    pub address: String,
+    pub verdict: RecipientVerdict,
+    /// Reply the verdict is based on; `None` for invalid addresses.
+    pub reply: Option<SmtpReply>,
+}
+
+/// 452 / x.5.3: this transaction holds no more recipients, a new one may (RFC 5321 4.5.3.1.10).
+fn too_many_recipients(reply: &SmtpReply) -> bool {
+    reply.code == 452
+        || reply
+            .enhanced
+            .is_some_and(|e| e.subject == 5 && e.detail == 3)
+}
+
+/// 4.7.x: the server limits how fast we may go; asking again now will not help.
+fn rate_limited(reply: &SmtpReply) -> bool {
+    reply.is_transient() && reply.enhanced.is_some_and(|e| e.subject == 7)
+}
+
+/// Milliseconds spent in each step of one transaction. Pipelined envelope commands share
+/// a round trip, so their time is split by when each reply arrived.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SendTimings {
+    pub mail_from_ms: u64,
+    pub rcpt_to_ms: u64,
+    pub data_ms: u64,
+    /// Writing the body until the final reply.
+    pub body_ms: u64,
+    pub total_ms: u64,
+}
+
+/// Outcome of a delivered message.
+#[derive(Debug, Clone)]
+pub struct SendResult {
+    /// Reply to the end of DATA (e.g. `250 2.0.0 queued as ABC`).
+    pub reply: SmtpReply,
+    /// `Message-ID` header of the message (generated unless set in `headers`).
+    pub message_id: Option<String>,
+    /// Id the server queued the message under, parsed from `reply`.
+    pub queue_id: Option<String>,
+    pub accepted: Vec<AcceptedRecipient>,
+    /// Recipients refused under [`RecipientPolicy::ContinueIfAnyAccepted`].
+    pub rejected: Vec<SmtpRecipientError>,
+    /// Host of the relay that accepted the message.
+    pub relay: String,
+    /// TLS protection of the session (`TlsPolicy::None` for plaintext).
+    pub tls: TlsPolicy,
+    /// SASL mechanism the session logged in with.
+    pub auth_mechanism: Option<AuthType>,
+    /// Bytes sent after DATA, dot-stuffing and the final `.` included.
+    pub size: usize,
+    pub timings: SendTimings,
+    /// Lines of this transaction, when `WorkerMailerOptions.transcript` is set.
+    pub transcript: Option<Transcript>,
+}
+
+type RecipientOutcome = (Vec<AcceptedRecipient>, Vec<SmtpRecipientError>);
+
+/// A message whose body was written and whose final reply is still to be read.
+struct InFlight {
+    message_id: Option<String>,
+    accepted: Vec<AcceptedRecipient>,
+    rejected: Vec<SmtpRecipientError>,
+    size: usize,
+    timings: SendTimings,
+    started_at: u64,
+    body_started_at: u64,
+    transcript_start: usize,
+}
+
 /// Options to create WorkerMailer.
 #[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
 pub struct WorkerMailerOptions {
     pub host: String,
     pub port: u16,
+    /// Relays to fail over between; when empty, `host`/`port` is the only one.
+    #[serde(default)]
+    pub endpoints: Vec<Endpoint>,
     #[serde(default)]
     pub secure: bool,
     #[serde(default = "default_start_tls")]
     pub start_tls: bool,
+    /// Overrides `secure`/`start_tls`, which map to `Implicit`, `StartTlsOpportunistic`
+    /// or `None`.
+    #[serde(default)]
+    pub tls: Option<TlsPolicy>,
+    /// Allow AUTH when the session is not encrypted (refused by default).
+    #[serde(default)]
+    pub allow_plaintext_auth: bool,
     pub credentials: Option<Credentials>,
     #[serde(default)]
     pub auth_type: Vec<AuthType>,
+    /// Name sent in EHLO/HELO: an FQDN or an address literal such as `[192.0.2.1]`.
+    /// Defaults to the From domain with `send`, else the username's domain, else `[127.0.0.1]`.
+    #[serde(default)]
+    pub client_hostname: Option<String>,
+    #[serde(default)]
+    pub recipient_policy: RecipientPolicy,
     #[serde(default)]
     pub log_level: LogLevel,
     pub dsn: Option<DsnOptions>,
+    /// Limit for opening the connection, the TLS upgrade and each write (0 = none).
     #[serde(default = "default_socket_timeout_ms")]
     pub socket_timeout_ms: u64,
+    /// Limit for each server reply (0 = none).
     #[serde(default = "default_response_timeout_ms")]
     pub response_timeout_ms: u64,
+    /// Overall limit for one `send_one`, from MAIL FROM to the final reply.
+    #[serde(default)]
+    pub message_timeout_ms: Option<u64>,
+    /// Messages sent on one connection before it is replaced by a fresh one.
+    #[serde(default)]
+    pub max_messages_per_connection: Option<u32>,
+    /// Transactions `send_many` keeps in flight when the server supports PIPELINING;
+    /// 1 waits for each final reply. SMTP only lets the next envelope travel with the
+    /// previous body, so values above 2 act as 2.
+    #[serde(default = "default_max_in_flight")]
+    pub max_in_flight: usize,
+    /// Retries for `WorkerMailer::send` and the queue consumer (none by default).
+    #[serde(default)]
+    pub retry: Option<RetryPolicy>,
+    /// Record the SMTP conversation (off by default).
+    #[serde(default)]
+    pub transcript: Option<TranscriptOptions>,
     #[serde(skip)]
     pub hooks: WorkerMailerHooks,
+    /// Fetches a fresh access token for OAuth credentials (not serialized for queue).
+    #[serde(skip)]
+    pub token_provider: Option<TokenProvider>,
 }
 
 fn default_start_tls() -> bool {
@@ -97,346 +556,2764 @@ fn default_socket_timeout_ms() -> u64 {
 fn default_response_timeout_ms() -> u64 {
     30_000
 }
+fn default_max_in_flight() -> usize {
+    2
+}
 
 impl Default for WorkerMailerOptions {
     fn default() -> Self {
         Self {
             host: String::new(),
             port: 587,
+            endpoints: vec![],
             secure: false,
             start_tls: true,
+            tls: None,
+            allow_plaintext_auth: false,
             credentials: None,
             auth_type: vec![],
+            client_hostname: None,
+            recipient_policy: RecipientPolicy::FailAll,
             log_level: LogLevel::Info,
             dsn: None,
             socket_timeout_ms: 60_000,
             response_timeout_ms: 30_000,
+            message_timeout_ms: None,
+            max_messages_per_connection: None,
+            max_in_flight: 2,
+            retry: None,
+            transcript: None,
             hooks: WorkerMailerHooks::default(),
+            token_provider: None,
         }
     }
 }
 
-/// SMTP client using Cloudflare Workers TCP Socket.
+/// SMTP client over a pluggable [`Transport`] (Cloudflare socket by default).
 pub struct WorkerMailer {
-    socket: Option<Socket>,
+    connector: Box<dyn Connector>,
+    /// `None` once the session is lost; the next `send_one` reconnects.
+    transport: Option<Box<dyn Transport>>,
+    /// Bytes read past the last complete reply.
+    replies: ReplyCodec,
+    /// Relays, with credentials and TLS policy filled in from the options.
+    endpoints: Vec<Endpoint>,
+    /// Index in `endpoints` of the current relay.
+    endpoint: usize,
+    /// Relay of the current session.
     host: String,
     port: u16,
-    secure: bool,
-    start_tls: bool,
+    requested_tls: TlsPolicy,
+    /// Protection actually in place: `None` while the session is in plaintext.
+    tls_policy: TlsPolicy,
+    allow_plaintext_auth: bool,
+    client_hostname: String,
+    recipient_policy: RecipientPolicy,
     auth_type: Vec<AuthType>,
     credentials: Option<Credentials>,
+    token_provider: Option<TokenProvider>,
+    log_level: LogLevel,
     logger: Logger,
     dsn: Option<DsnOptions>,
+    socket_timeout_ms: u64,
     response_timeout_ms: u64,
+    message_timeout_ms: Option<u64>,
+    max_messages_per_connection: Option<u32>,
+    max_in_flight: usize,
+    /// Transactions started on the current connection.
+    messages_on_connection: u32,
+    /// Step in progress, reported when a timeout fires.
+    phase: SmtpPhase,
+    /// Whether the current transaction got as far as sending the message body.
+    body_sent: bool,
+    /// SASL mechanism of the current session.
+    auth_mechanism: Option<AuthType>,
+    /// Sessions opened so far.
+    sessions: u32,
+    /// Phase timings of the current transaction, and when it and its last phase began.
+    timings: SendTimings,
+    started_at: u64,
+    lap_at: u64,
+    /// Recorded conversation, when enabled, and where the current transaction starts in it.
+    transcript: Option<Transcript>,
+    transcript_start: usize,
+    redact_body: bool,
     hooks: WorkerMailerHooks,
-    supports_dsn: bool,
-    allow_auth: bool,
-    auth_type_supported: Vec<AuthType>,
-    supports_start_tls: bool,
+    capabilities: ServerCapabilities,
 }
 
 impl WorkerMailer {
     /// Connect to SMTP server and perform EHLO/STARTTLS/AUTH.
-    pub async fn connect(options: WorkerMailerOptions) -> Result<Self, worker::Error> {
-        let socket = ConnectionBuilder::new()
-            .allow_half_open(false)
-            .connect(options.host.clone(), options.port)?;
-
-        let mut mailer = Self {
-            socket: Some(socket),
-            host: options.host.clone(),
-            port: options.port,
-            secure: options.secure,
-            start_tls: options.start_tls,
-            auth_type: options.auth_type,
-            credentials: options.credentials,
-            logger: Logger::new(
-                options.log_level,
-                format!("[WorkerMailer:{}:{}]", options.host, options.port),
-            ),
-            dsn: options.dsn,
-            response_timeout_ms: options.response_timeout_ms,
-            hooks: options.hooks,
-            supports_dsn: false,
-            allow_auth: false,
-            auth_type_supported: vec![],
-            supports_start_tls: false,
-        };
+    pub async fn connect(options: WorkerMailerOptions) -> Result<Self, MailerError> {
+        Self::connect_with(options, default_connector()).await
+    }
 
-        mailer.initialize_smtp_session().await?;
-        if let Some(ref f) = mailer.hooks.on_connect {
-            f();
+    /// Like [`connect`](Self::connect), opening the transport with `connector`.
+    pub async fn connect_with(
+        options: WorkerMailerOptions,
+        connector: Box<dyn Connector>,
+    ) -> Result<Self, MailerError> {
+        let mut mailer = Self::new(options, connector)?;
+        if let Err(e) = mailer.open().await {
+            mailer.emit_error(None, &e).await;
+            return Err(e);
         }
         Ok(mailer)
     }
 
-    /// Send one email (connect, send, close).
-    pub async fn send(
+    /// Validated mailer without a session; `send_one` connects on first use.
+    fn new(
         options: WorkerMailerOptions,
-        email_options: EmailOptions,
-    ) -> Result<(), worker::Error> {
-        let mut mailer = Self::connect(options).await?;
-        mailer.send_one(email_options).await?;
-        mailer.close(None).await
-    }
+        connector: Box<dyn Connector>,
+    ) -> Result<Self, MailerError> {
+        let client_hostname = match options.client_hostname.as_deref() {
+            Some(name) => normalize_client_hostname(name).ok_or_else(|| {
+                MailerError::Config(format!("Invalid client_hostname: {:?}", name))
+            })?,
+            None => options
+                .credentials
+                .as_ref()
+                .and_then(|c| domain_identity(c.username()))
+                .unwrap_or_else(|| "[127.0.0.1]".to_string()),
+        };
+        let requested_tls = options.tls.unwrap_or(if options.secure {
+            TlsPolicy::Implicit
+        } else if options.start_tls {
+            TlsPolicy::StartTlsOpportunistic
+        } else {
+            TlsPolicy::None
+        });
 
-    /// Send one email on this connection.
-    pub async fn send_one(
-        &mut self,
-        email_options: EmailOptions,
-    ) -> Result<String, worker::Error> {
-        let mut email = Email::new(email_options.clone()).map_err(|e| {
-            worker::Error::RustError(match e {
-                crate::email::EmailBuildError::InvalidContent(ic) => ic.to_string(),
-                crate::email::EmailBuildError::InvalidEmail(ie) => ie.to_string(),
+        let endpoints = if options.endpoints.is_empty() {
+            vec![Endpoint::new(options.host.clone(), options.port)]
+        } else {
+            options.endpoints
+        };
+        let endpoints = endpoints
+            .into_iter()
+            .map(|e| Endpoint {
+                credentials: e.credentials.or_else(|| options.credentials.clone()),
+                tls: Some(e.tls.unwrap_or(requested_tls)),
+                ..e
             })
-        })?;
-
-        self.cmd_mail(&email).await?;
-        self.cmd_rcpt(&email).await?;
-        self.cmd_data().await?;
-        let body = email.get_email_data();
-        self.write(&body).await?;
-        let response = self.read_response().await.map_err(|e| worker::Error::RustError(e))?;
-        if !response.starts_with('2') {
-            return Err(worker::Error::RustError(format!("Failed to send body: {}", response)));
-        }
-        if let Some(ref f) = self.hooks.on_sent {
-            f(&email_options, &response);
-        }
-        Ok(response)
-    }
+            .collect();
 
-    async fn initialize_smtp_session(&mut self) -> Result<(), worker::Error> {
-        self.greet().await?;
-        self.ehlo().await?;
-
-        if self.start_tls && !self.secure && self.supports_start_tls {
-            let s = self.socket.take().unwrap();
-            self.socket = Some(s.start_tls());
-            self.ehlo().await?;
-        }
-
-        self.auth().await?;
-        Ok(())
+        Ok(Self {
+            connector,
+            transport: None,
+            replies: ReplyCodec::default(),
+            endpoints,
+            endpoint: 0,
+            host: options.host,
+            port: options.port,
+            requested_tls,
+            tls_policy: TlsPolicy::None,
+            allow_plaintext_auth: options.allow_plaintext_auth,
+            client_hostname,
+            recipient_policy: options.recipient_policy,
+            auth_type: options.auth_type,
+            credentials: options.credentials,
+            token_provider: options.token_provider,
+            log_level: options.log_level,
+            logger: Logger::new(options.log_level, "[WorkerMailer]"),
+            dsn: options.dsn,
+            socket_timeout_ms: options.socket_timeout_ms,
+            response_timeout_ms: options.response_timeout_ms,
+            message_timeout_ms: options.message_timeout_ms,
+            max_messages_per_connection: options.max_messages_per_connection,
+            max_in_flight: options.max_in_flight,
+            messages_on_connection: 0,
+            phase: SmtpPhase::Connect,
+            body_sent: false,
+            auth_mechanism: None,
+            sessions: 0,
+            timings: SendTimings::default(),
+            started_at: 0,
+            lap_at: 0,
+            transcript: options.transcript.as_ref().map(|_| Transcript::default()),
+            transcript_start: 0,
+            redact_body: options.transcript.is_none_or(|t| t.redact_body),
+            hooks: options.hooks,
+            capabilities: ServerCapabilities::default(),
+        })
     }
 
-    async fn read_response(&mut self) -> Result<String, String> {
-        let mut buf = vec![0u8; 4096];
-        let mut response = String::new();
-        loop {
-            let n = self.socket.as_mut().unwrap().read(&mut buf).await.map_err(|e| format!("read error: {}", e))?;
-            if n == 0 {
-                break;
-            }
-            let s = decode(&buf[..n]).map_err(|e| e.to_string())?;
-            self.logger.debug(&format!("SMTP response:\n{}", s));
-            response.push_str(&s);
-            if !response.ends_with('\n') {
-                continue;
-            }
-            let lines: Vec<&str> = response.lines().collect();
-            let last = lines.iter().rev().nth(1).unwrap_or(&"");
-            if last.len() >= 4 {
-                let _code = &last[..3];
-                let cont = last.chars().nth(3).unwrap_or(' ');
-                if cont == '-' {
-                    continue;
+    /// Connect and run EHLO/STARTTLS/AUTH on a fresh session, moving down the relay list
+    /// while relays are unreachable, fail TLS or answer the greeting with 4xx.
+    async fn open(&mut self) -> Result<(), MailerError> {
+        let mut last_error = None;
+        for index in failover_order(&self.endpoints) {
+            self.select_endpoint(index);
+            match self.open_endpoint().await {
+                Ok(()) => {
+                    self.sessions += 1;
+                    self.emit_connect().await;
+                    return Ok(());
                 }
+                Err(e) if relay_unavailable(&e, self.phase) => {
+                    self.logger.warn(&format!("Relay unavailable: {}", e));
+                    last_error = Some(e);
+                }
+                Err(e) => return Err(e),
             }
-            break;
         }
-        Ok(response)
+        Err(last_error.unwrap_or_else(|| MailerError::Config("No SMTP endpoint".into())))
     }
 
-    async fn write_line(&mut self, line: &str) -> Result<(), worker::Error> {
-        self.write(&format!("{}\r\n", line)).await
+    fn select_endpoint(&mut self, index: usize) {
+        let endpoint = &self.endpoints[index];
+        self.endpoint = index;
+        self.host = endpoint.host.clone();
+        self.port = endpoint.port;
+        self.requested_tls = endpoint.tls.unwrap_or(self.requested_tls);
+        self.credentials = endpoint.credentials.clone();
+        self.logger = Logger::new(
+            self.log_level,
+            format!("[WorkerMailer:{}:{}]", self.host, self.port),
+        );
     }
 
-    async fn write(&mut self, data: &str) -> Result<(), worker::Error> {
-        self.logger.debug(&format!("Write:\n{}", data));
-        let bytes = encode(data);
-        self.socket.as_mut().unwrap().write_all(&bytes).await?;
-        self.socket.as_mut().unwrap().flush().await?;
+    async fn open_endpoint(&mut self) -> Result<(), MailerError> {
+        let secure = self.requested_tls == TlsPolicy::Implicit;
+        let ms = self.socket_timeout_ms;
+        self.phase = SmtpPhase::Connect;
+        let note = format!("connecting to {}:{}", self.host, self.port);
+        self.record(Direction::Note, &note);
+        let transport = timeout(ms, self.connector.connect(&self.host, self.port, secure))
+            .await
+            .ok_or_else(|| SmtpTimeoutError::new(SmtpPhase::Connect, ms))?
+            .map_err(|e| connection_error(format!("Failed to connect: {}", e)))?;
+        self.transport = Some(transport);
+        self.replies.clear();
+        self.messages_on_connection = 0;
+        self.auth_mechanism = None;
+        self.tls_policy = if secure {
+            TlsPolicy::Implicit
+        } else {
+            TlsPolicy::None
+        };
+        self.capabilities = ServerCapabilities::default();
+        if let Err(e) = self.initialize_smtp_session().await {
+            self.abort_connection().await;
+            return Err(e);
+        }
         Ok(())
     }
 
-    async fn greet(&mut self) -> Result<(), worker::Error> {
-        let response = self.read_response().await.map_err(|e| worker::Error::RustError(e))?;
-        if !response.starts_with("220") {
-            return Err(worker::Error::RustError(format!(
-                "Failed to connect: {}",
-                response
-            )));
-        }
-        Ok(())
+    /// Drop the current session and open a new one, authenticating again.
+    async fn reconnect(&mut self) -> Result<(), MailerError> {
+        self.logger.info("Reconnecting");
+        self.abort_connection().await;
+        self.open().await
     }
 
-    async fn ehlo(&mut self) -> Result<(), worker::Error> {
-        self.write_line("EHLO 127.0.0.1").await?;
-        let response = self.read_response().await.map_err(|e| worker::Error::RustError(e))?;
-        if response.starts_with("421") {
-            return Err(worker::Error::RustError(format!("EHLO failed: {}", response)));
+    async fn emit_connect(&self) {
+        if self.sessions > 1 {
+            if let Some(ref f) = self.hooks.on_reconnect {
+                f(&self.host);
+            }
+            if let Some(hook) = self.hooks.on_reconnect_async.clone() {
+                hook.call(self.host.clone()).await;
+            }
         }
-        if !response.starts_with('2') {
-            self.helo().await?;
-            return Ok(());
+        if let Some(ref f) = self.hooks.on_connect {
+            f();
+        }
+        if let Some(hook) = self.hooks.on_connect_async.clone() {
+            hook.call(()).await;
         }
-        self.parse_capabilities(&response);
-        Ok(())
     }
 
-    async fn helo(&mut self) -> Result<(), worker::Error> {
-        self.write_line("HELO 127.0.0.1").await?;
-        let response = self.read_response().await.map_err(|e| worker::Error::RustError(e))?;
-        if !response.starts_with('2') {
-            return Err(worker::Error::RustError(format!("HELO failed: {}", response)));
+    async fn emit_error(&self, email_options: Option<&EmailOptions>, error: &MailerError) {
+        if self.hooks.on_error.is_none() && self.hooks.on_error_async.is_none() {
+            return;
         }
-        Ok(())
+        let summary = email_options.map(EmailSummary::from);
+        self.report_error(summary.as_ref(), error).await;
     }
 
-    fn parse_capabilities(&mut self, response: &str) {
-        if response.to_uppercase().contains("AUTH") {
-            self.allow_auth = true;
+    async fn report_error(&self, summary: Option<&EmailSummary>, error: &MailerError) {
+        if let Some(ref f) = self.hooks.on_error {
+            f(summary, error);
         }
-        if response.to_uppercase().contains("AUTH") && response.to_uppercase().contains("PLAIN") {
-            self.auth_type_supported.push(AuthType::Plain);
+        if let Some(hook) = self.hooks.on_error_async.clone() {
+            hook.call((summary.cloned(), error.clone())).await;
         }
-        if response.to_uppercase().contains("AUTH") && response.to_uppercase().contains("LOGIN") {
-            self.auth_type_supported.push(AuthType::Login);
-        }
-        if response.to_uppercase().contains("AUTH") && response.to_uppercase().contains("CRAM-MD5") {
-            self.auth_type_supported.push(AuthType::CramMd5);
+    }
+
+    async fn emit_rejected(&self, error: &SmtpRecipientError) {
+        if let Some(ref f) = self.hooks.on_recipient_rejected {
+            f(error);
         }
-        if response.to_uppercase().contains("STARTTLS") {
-            self.supports_start_tls = true;
+        if let Some(hook) = self.hooks.on_recipient_rejected_async.clone() {
+            hook.call(error.clone()).await;
         }
-        if response.to_uppercase().contains("DSN") {
-            self.supports_dsn = true;
+    }
+
+    fn record(&mut self, direction: Direction, data: &str) {
+        if let Some(ref mut transcript) = self.transcript {
+            transcript.push(direction, data);
         }
     }
 
-    async fn auth(&mut self) -> Result<(), worker::Error> {
-        if !self.allow_auth {
-            return Ok(());
+    /// Conversation recorded so far (all sessions), when `options.transcript` is set.
+    pub fn transcript(&self) -> Option<&Transcript> {
+        self.transcript.as_ref()
+    }
+
+    /// Hands over the recorded conversation and starts a new one.
+    pub fn take_transcript(&mut self) -> Option<Transcript> {
+        self.transcript_start = 0;
+        self.transcript.as_mut().map(std::mem::take)
+    }
+
+    /// Host of the relay this session is connected to.
+    pub fn relay(&self) -> &str {
+        &self.host
+    }
+
+    /// TLS protection achieved for this session: `TlsPolicy::None` means plaintext.
+    pub fn tls_policy(&self) -> TlsPolicy {
+        self.tls_policy
+    }
+
+    /// Extensions the server advertised in its last EHLO (empty after a HELO fallback).
+    pub fn capabilities(&self) -> &ServerCapabilities {
+        &self.capabilities
+    }
+
+    /// Send one email (connect, send, close), retried under `options.retry`.
+    pub async fn send(
+        options: WorkerMailerOptions,
+        email_options: EmailOptions,
+    ) -> Result<SendResult, MailerError> {
+        Self::send_with_transcript(options, email_options).await.0
+    }
+
+    /// Like [`send`](Self::send), also returning the whole conversation (every attempt),
+    /// failed or not, when `options.transcript` is set.
+    pub async fn send_with_transcript(
+        mut options: WorkerMailerOptions,
+        email_options: EmailOptions,
+    ) -> (Result<SendResult, MailerError>, Option<Transcript>) {
+        if options.client_hostname.is_none() {
+            options.client_hostname = domain_identity(email_options.from.email());
         }
-        let creds = match self.credentials.clone() {
-            Some(c) => c,
-            None => return Err(worker::Error::RustError("Auth required but no credentials".into())),
+        let policy = options.retry.take().unwrap_or(RetryPolicy {
+            max_attempts: 1,
+            ..Default::default()
+        });
+        let mut mailer = match Self::new(options, default_connector()) {
+            Ok(mailer) => mailer,
+            Err(e) => return (Err(e), None),
+        };
+        let result = match mailer.send_one_with_retry(email_options, &policy).await {
+            Ok(result) => mailer.close(None).await.map(|()| result),
+            Err(e) => {
+                let _ = mailer.close(Some(e.clone())).await;
+                Err(e)
+            }
         };
+        (result, mailer.take_transcript())
+    }
 
-        if self.auth_type_supported.contains(&AuthType::Plain) && self.auth_type.contains(&AuthType::Plain) {
-            self.auth_plain(&creds).await?;
-        } else if self.auth_type_supported.contains(&AuthType::Login) && self.auth_type.contains(&AuthType::Login) {
-            self.auth_login(&creds).await?;
-        } else if self.auth_type_supported.contains(&AuthType::CramMd5) && self.auth_type.contains(&AuthType::CramMd5) {
-            self.auth_cram_md5(&creds).await?;
-        } else {
-            return Err(worker::Error::RustError("No supported auth method".into()));
+    /// [`send_one`](Self::send_one), tried again under `policy` while it fails with
+    /// transient errors. Returns the last error once the policy gives up.
+    pub async fn send_one_with_retry(
+        &mut self,
+        email_options: EmailOptions,
+        policy: &RetryPolicy,
+    ) -> Result<SendResult, MailerError> {
+        let mut email_options = self.before_send(email_options).await?;
+        let started = now_millis();
+        let mut attempt = 1;
+        loop {
+            let error = match self.deliver(&mut email_options).await {
+                Ok(result) => return Ok(result),
+                Err(e) => e,
+            };
+            let elapsed = now_millis().saturating_sub(started);
+            let Some(delay) = policy.next_delay(attempt, elapsed, &error) else {
+                return Err(error);
+            };
+            self.logger.warn(&format!(
+                "Attempt {} failed, retrying in {} ms: {}",
+                attempt, delay, error
+            ));
+            sleep(delay).await;
+            attempt += 1;
         }
-        Ok(())
     }
 
-    async fn auth_plain(&mut self, creds: &Credentials) -> Result<(), worker::Error> {
-        let blob = format!("\u{0}{}\u{0}{}", creds.username, creds.password);
-        let b64 = B64.encode(blob.as_bytes());
-        self.write_line(&format!("AUTH PLAIN {}", b64)).await?;
-        let r = self.read_response().await.map_err(|e| worker::Error::RustError(e))?;
-        if !r.starts_with('2') {
-            return Err(worker::Error::RustError(format!("AUTH PLAIN failed: {}", r)));
-        }
-        Ok(())
+    /// Send one email on this connection. A lost session, or one that reached
+    /// `max_messages_per_connection`, is reopened first, and once more if the server drops
+    /// it (421, 4.7.0 or closed socket) before the message body was sent.
+    pub async fn send_one(
+        &mut self,
+        email_options: EmailOptions,
+    ) -> Result<SendResult, MailerError> {
+        let mut email_options = self.before_send(email_options).await?;
+        self.deliver(&mut email_options).await
     }
 
-    async fn auth_login(&mut self, creds: &Credentials) -> Result<(), worker::Error> {
-        self.write_line("AUTH LOGIN").await?;
-        let r = self.read_response().await.map_err(|e| worker::Error::RustError(e))?;
-        if !r.starts_with('3') {
-            return Err(worker::Error::RustError(format!("AUTH LOGIN: {}", r)));
-        }
-        let u = B64.encode(creds.username.as_bytes());
-        self.write_line(&u).await?;
-        let r = self.read_response().await.map_err(|e| worker::Error::RustError(e))?;
-        if !r.starts_with('3') {
-            return Err(worker::Error::RustError(format!("AUTH LOGIN user: {}", r)));
+    /// Send several emails over this session, one result per email: a failed message does
+    /// not stop the batch. With PIPELINING and `max_in_flight` above 1, each envelope is
+    /// written before the final reply to the previous body is read.
+    pub async fn send_many(
+        &mut self,
+        emails: Vec<EmailOptions>,
+    ) -> Vec<Result<SendResult, MailerError>> {
+        let mut results = Vec::with_capacity(emails.len());
+        let mut in_flight: Option<(EmailOptions, InFlight)> = None;
+        for email_options in emails {
+            let prepared = self.before_send(email_options).await;
+            let overlap = self.max_in_flight > 1
+                && self.message_timeout_ms.is_none()
+                && self.capabilities.pipelining
+                && self.transport.is_some()
+                && !self.limit_reached();
+            let mut email_options = match prepared {
+                Ok(email_options) if overlap => email_options,
+                prepared => {
+                    if let Some((options, previous)) = in_flight.take() {
+                        results.push(self.finish(&options, previous).await);
+                    }
+                    results.push(match prepared {
+                        Ok(mut email_options) => self.deliver(&mut email_options).await,
+                        Err(e) => Err(e),
+                    });
+                    continue;
+                }
+            };
+            let sent = match Email::lend(&mut email_options) {
+                Ok(mut email) => {
+                    self.body_sent = false;
+                    self.messages_on_connection += 1;
+                    self.start_clock();
+                    let written = self.write_envelope(&email).await;
+                    if let Some((options, previous)) = in_flight.take() {
+                        results.push(self.finish(&options, previous).await);
+                    }
+                    let envelope = match written {
+                        Ok(rcpts) => self.read_envelope(rcpts).await,
+                        Err(e) => Err(e),
+                    };
+                    match envelope {
+                        Ok(outcome) => match self.write_body(&mut email).await {
+                            Ok(size) => Ok(self.in_flight(&email, outcome, size)),
+                            Err(e) => Err(e),
+                        },
+                        Err(e) => Err(e),
+                    }
+                }
+                Err(e) => {
+                    if let Some((options, previous)) = in_flight.take() {
+                        results.push(self.finish(&options, previous).await);
+                    }
+                    Err(MailerError::from(e))
+                }
+            };
+            match sent {
+                Ok(sent) => in_flight = Some((email_options, sent)),
+                Err(e) => {
+                    self.recover(&e).await;
+                    if session_lost(&e) && !self.body_sent {
+                        results.push(self.deliver(&mut email_options).await);
+                    } else {
+                        let e = if self.body_sent { unconfirmed(e) } else { e };
+                        self.emit_error(Some(&email_options), &e).await;
+                        results.push(Err(e));
+                    }
+                }
+            }
         }
-        let p = B64.encode(creds.password.as_bytes());
-        self.write_line(&p).await?;
-        let r = self.read_response().await.map_err(|e| worker::Error::RustError(e))?;
-        if !r.starts_with('2') {
-            return Err(worker::Error::RustError(format!("AUTH LOGIN: {}", r)));
+        if let Some((options, previous)) = in_flight.take() {
+            results.push(self.finish(&options, previous).await);
         }
-        Ok(())
+        results
     }
 
-    async fn auth_cram_md5(&mut self, _creds: &Credentials) -> Result<(), worker::Error> {
-        self.write_line("AUTH CRAM-MD5").await?;
-        let r = self.read_response().await.map_err(|e| worker::Error::RustError(e))?;
-        let rest = r.strip_prefix("334 ").unwrap_or("").trim();
-        let _challenge = B64
-            .decode(rest)
-            .map_err(|_| worker::Error::RustError("Invalid CRAM-MD5 challenge".into()))?;
-        // HMAC-MD5 in WASM would need a crate (e.g. hmac + md5). Use PLAIN or LOGIN for now.
-        return Err(worker::Error::RustError(
-            "CRAM-MD5 not fully implemented in this example; use PLAIN or LOGIN".into(),
-        ));
-    }
-
-    async fn cmd_mail(&mut self, email: &Email) -> Result<(), worker::Error> {
-        let mut msg = format!("MAIL FROM: <{}>", email.from.email);
-        if self.supports_dsn {
-            msg.push_str(" ");
-            // optional RET= and ENVID
-        }
-        self.write_line(&msg).await?;
-        let r = self.read_response().await.map_err(|e| worker::Error::RustError(e))?;
-        if !r.starts_with('2') {
-            return Err(worker::Error::RustError(format!("MAIL FROM failed: {}", r)));
+    /// Runs the `before_send` hooks, which may rewrite the message or veto it.
+    async fn before_send(
+        &self,
+        mut email_options: EmailOptions,
+    ) -> Result<EmailOptions, MailerError> {
+        if let Some(ref f) = self.hooks.before_send {
+            if let Err(reason) = f(&mut email_options) {
+                let error = MailerError::Vetoed(reason);
+                self.emit_error(Some(&email_options), &error).await;
+                return Err(error);
+            }
         }
-        Ok(())
+        let Some(hook) = self.hooks.before_send_async.clone() else {
+            return Ok(email_options);
+        };
+        // The message moves into the hook, so a veto is reported with its summary.
+        let summary = EmailSummary::from(&email_options);
+        let error = match hook.call(email_options).await {
+            Ok(edited) => return Ok(edited),
+            Err(reason) => MailerError::Vetoed(reason),
+        };
+        self.report_error(Some(&summary), &error).await;
+        Err(error)
     }
 
-    async fn cmd_rcpt(&mut self, email: &Email) -> Result<(), worker::Error> {
-        let mut all = email.to.clone();
-        if let Some(ref cc) = email.cc {
-            all.extend(cc.iter().cloned());
+    /// One message, reopening the session as needed; failures go to `on_error`.
+    async fn deliver(
+        &mut self,
+        email_options: &mut EmailOptions,
+    ) -> Result<SendResult, MailerError> {
+        let result = self.deliver_inner(email_options).await;
+        if let Err(ref e) = result {
+            self.emit_error(Some(email_options), e).await;
         }
-        if let Some(ref bcc) = email.bcc {
-            all.extend(bcc.iter().cloned());
+        result
+    }
+
+    async fn deliver_inner(
+        &mut self,
+        email_options: &mut EmailOptions,
+    ) -> Result<SendResult, MailerError> {
+        if self.transport.is_none() {
+            self.open().await?;
+        } else if self.limit_reached() {
+            self.logger
+                .info("Message limit for this connection reached");
+            self.quit().await;
+            self.reconnect().await?;
         }
-        for user in &all {
-            let line = format!("RCPT TO: <{}>", user.email);
-            self.write_line(&line).await?;
-            let r = self.read_response().await.map_err(|e| worker::Error::RustError(e))?;
-            if !r.starts_with('2') {
-                return Err(worker::Error::RustError(format!(
-                    "RCPT TO failed for {}: {}",
-                    user.email, r
-                )));
+        match self.attempt(email_options).await {
+            Err(e) if session_lost(&e) && !self.body_sent => {
+                self.logger
+                    .info(&format!("Session lost before DATA: {}", e));
+                self.reconnect().await?;
+                self.attempt(email_options).await
             }
+            result => result,
         }
-        Ok(())
     }
 
-    async fn cmd_data(&mut self) -> Result<(), worker::Error> {
-        self.write_line("DATA").await?;
-        let r = self.read_response().await.map_err(|e| worker::Error::RustError(e))?;
-        if !r.starts_with('3') {
-            return Err(worker::Error::RustError(format!("DATA failed: {}", r)));
-        }
-        Ok(())
+    fn limit_reached(&self) -> bool {
+        self.max_messages_per_connection
+            .is_some_and(|max| self.messages_on_connection >= max)
     }
 
-    /// Close the connection.
-    pub async fn close(&mut self, _error: Option<worker::Error>) -> Result<(), worker::Error> {
-        let _ = self.write_line("QUIT").await;
-        let _ = self.read_response().await;
-        if let Some(ref mut s) = self.socket {
-            s.close().await?;
+    /// Final reply of an overlapped message. The transaction is over either way, so only
+    /// a lost session needs cleaning up.
+    async fn finish(
+        &mut self,
+        email_options: &EmailOptions,
+        in_flight: InFlight,
+    ) -> Result<SendResult, MailerError> {
+        let error = match self.final_reply(email_options, in_flight).await {
+            Ok(result) => return Ok(result),
+            Err(e) => e,
+        };
+        if session_lost(&error) {
+            self.abort_connection().await;
         }
-        if let Some(ref f) = self.hooks.on_close {
-            f(None);
+        let error = unconfirmed(error);
+        self.emit_error(Some(email_options), &error).await;
+        Err(error)
+    }
+
+    /// One transaction within `message_timeout_ms`, cleaned up on failure.
+    async fn attempt(
+        &mut self,
+        email_options: &mut EmailOptions,
+    ) -> Result<SendResult, MailerError> {
+        self.body_sent = false;
+        let result = match self.message_timeout_ms {
+            None => self.transaction(email_options).await,
+            Some(ms) => match timeout(ms, self.transaction(email_options)).await {
+                Some(result) => result,
+                None => Err(self.timed_out(ms).await),
+            },
+        };
+        match result {
+            Err(e) => {
+                self.recover(&e).await;
+                Err(if self.body_sent { unconfirmed(e) } else { e })
+            }
+            result => result,
+        }
+    }
+
+    /// Leaves the session ready for the next message: RSET after a failed transaction,
+    /// or drop the connection when the server closed it or RSET fails.
+    async fn recover(&mut self, error: &MailerError) {
+        // These are raised before any command of the transaction is written.
+        let nothing_sent = matches!(
+            error,
+            MailerError::InvalidEmail(_)
+                | MailerError::InvalidContent(_)
+                | MailerError::Config(_)
+                | MailerError::Vetoed(_)
+                | MailerError::Unsupported(_)
+        );
+        if self.transport.is_none() || nothing_sent {
+            return;
+        }
+        if session_lost(error) {
+            self.abort_connection().await;
+            return;
+        }
+        if let Err(e) = self.cmd_rset().await {
+            self.logger
+                .warn(&format!("RSET failed, dropping the connection: {}", e));
+            self.abort_connection().await;
+        }
+    }
+
+    /// Check whether the relay would accept mail for `addresses`, without sending any:
+    /// MAIL FROM, one RCPT TO per address, then RSET instead of DATA. A transaction that
+    /// is full (452) is replaced by a new one. Once the server limits the rate (4.7.x) or
+    /// closes the session (421), the remaining addresses are `Deferred` without asking.
+    pub async fn verify_recipients<S: AsRef<str>>(
+        &mut self,
+        from: &str,
+        addresses: &[S],
+    ) -> Result<Vec<RecipientVerification>, MailerError> {
+        let result = self.verify_recipients_inner(from, addresses).await;
+        if let Err(ref e) = result {
+            self.emit_error(None, e).await;
+        }
+        result
+    }
+
+    async fn verify_recipients_inner<S: AsRef<str>>(
+        &mut self,
+        from: &str,
+        addresses: &[S],
+    ) -> Result<Vec<RecipientVerification>, MailerError> {
+        if !is_valid_email(from) {
+            return Err(InvalidEmailError::new(
+                format!("Invalid email address(es): {}", from),
+                vec![from.to_string()],
+            )
+            .into());
+        }
+        if self.transport.is_none() {
+            self.open().await?;
+        } else if self.limit_reached() {
+            self.quit().await;
+            self.reconnect().await?;
+        }
+        self.body_sent = false;
+        let result = self.verify(from, addresses).await;
+        if let Err(ref e) = result {
+            self.recover(e).await;
+        }
+        result
+    }
+
+    async fn verify<S: AsRef<str>>(
+        &mut self,
+        from: &str,
+        addresses: &[S],
+    ) -> Result<Vec<RecipientVerification>, MailerError> {
+        let mut verdicts = Vec::with_capacity(addresses.len());
+        let mut in_transaction = 0;
+        let mut index = 0;
+        while let Some(address) = addresses.get(index).map(AsRef::as_ref) {
+            if !is_valid_email(address) {
+                verdicts.push(RecipientVerification {
+                    address: address.to_string(),
+                    verdict: RecipientVerdict::Rejected,
+                    reply: None,
+                });
+                index += 1;
+                continue;
+            }
+            if in_transaction == 0 {
+                self.phase = SmtpPhase::MailFrom;
+                self.messages_on_connection += 1;
+                self.write_line(&format!("MAIL FROM: <{}>", from)).await?;
+                let r = self.read_response().await?;
+                if !r.is_positive() {
+                    return Err(reply_error("MAIL FROM failed", r));
+                }
+            }
+            self.phase = SmtpPhase::RcptTo;
+            self.write_line(&format!("RCPT TO: <{}>", address)).await?;
+            let reply = self.read_response().await?;
+            if too_many_recipients(&reply) && in_transaction > 0 {
+                // Ask again in a fresh transaction.
+                self.cmd_rset().await?;
+                in_transaction = 0;
+                continue;
+            }
+            in_transaction += 1;
+            index += 1;
+            let stop = reply.code == 421 || rate_limited(&reply);
+            let verdict = if reply.is_positive() {
+                RecipientVerdict::Accepted
+            } else if reply.is_permanent() {
+                RecipientVerdict::Rejected
+            } else {
+                RecipientVerdict::Deferred
+            };
+            verdicts.push(RecipientVerification {
+                address: address.to_string(),
+                verdict,
+                reply: Some(reply.clone()),
+            });
+            if stop {
+                verdicts.extend(addresses[index..].iter().map(|a| RecipientVerification {
+                    address: a.as_ref().to_string(),
+                    verdict: RecipientVerdict::Deferred,
+                    reply: Some(reply.clone()),
+                }));
+                if reply.code == 421 {
+                    self.abort_connection().await;
+                    return Ok(verdicts);
+                }
+                break;
+            }
+        }
+        if in_transaction > 0 {
+            self.cmd_rset().await?;
+        }
+        Ok(verdicts)
+    }
+
+    /// Abort the current mail transaction (RSET).
+    pub async fn reset(&mut self) -> Result<(), MailerError> {
+        let result = self.cmd_rset().await;
+        if let Err(ref e) = result {
+            self.emit_error(None, e).await;
+        }
+        result
+    }
+
+    async fn cmd_rset(&mut self) -> Result<(), MailerError> {
+        self.phase = SmtpPhase::Reset;
+        self.write_line("RSET").await?;
+        let r = self.read_response().await?;
+        if !r.is_positive() {
+            return Err(reply_error("RSET failed", r));
+        }
+        Ok(())
+    }
+
+    /// Check that the session is still alive (NOOP).
+    pub async fn noop(&mut self) -> Result<(), MailerError> {
+        let result = self.cmd_noop().await;
+        if let Err(ref e) = result {
+            self.emit_error(None, e).await;
+        }
+        result
+    }
+
+    async fn cmd_noop(&mut self) -> Result<(), MailerError> {
+        self.phase = SmtpPhase::Noop;
+        self.write_line("NOOP").await?;
+        let r = self.read_response().await?;
+        if !r.is_positive() {
+            return Err(reply_error("NOOP failed", r));
+        }
+        Ok(())
+    }
+
+    async fn transaction(
+        &mut self,
+        email_options: &mut EmailOptions,
+    ) -> Result<SendResult, MailerError> {
+        // The bodies and attachments are lent to `email`, not copied, until it drops.
+        let mut email = Email::lend(email_options)?;
+        self.messages_on_connection += 1;
+        self.start_clock();
+
+        let outcome = if self.capabilities.pipelining {
+            let rcpts = self.write_envelope(&email).await?;
+            self.read_envelope(rcpts).await?
+        } else {
+            self.cmd_mail(&email).await?;
+            let recipients = self.cmd_rcpt(&email).await?;
+            self.cmd_data().await?;
+            recipients
+        };
+        let size = self.write_body(&mut email).await?;
+        let in_flight = self.in_flight(&email, outcome, size);
+        drop(email);
+        self.final_reply(email_options, in_flight).await
+    }
+
+    fn start_clock(&mut self) {
+        self.timings = SendTimings::default();
+        self.started_at = now_millis();
+        self.lap_at = self.started_at;
+        self.transcript_start = self.transcript.as_ref().map_or(0, Transcript::len);
+    }
+
+    /// Milliseconds since the previous phase ended.
+    fn lap(&mut self) -> u64 {
+        let now = now_millis();
+        let lap = now.saturating_sub(self.lap_at);
+        self.lap_at = now;
+        lap
+    }
+
+    /// Streams the body, dot-stuffed, in writes of about `BODY_WRITE_SIZE` bytes; returns
+    /// its size in bytes.
+    async fn write_body(&mut self, email: &mut Email) -> Result<usize, MailerError> {
+        self.body_sent = true;
+        let mut stuffer = DotStuffer::default();
+        let mut buf = String::with_capacity(BODY_WRITE_SIZE + BODY_WRITE_SIZE / 4);
+        let mut size = 0;
+        let mut chunks = email.chunks();
+        loop {
+            let chunk = chunks.next();
+            match chunk {
+                Some(ref chunk) => stuffer.stuff(chunk, &mut buf),
+                None => buf.push_str(DATA_END),
+            }
+            if chunk.is_some() && buf.len() < BODY_WRITE_SIZE {
+                continue;
+            }
+            if self.logger.enabled(LogLevel::Debug) {
+                self.logger.debug(&format!("Write:\n{}", buf));
+            }
+            self.send_bytes(buf.as_bytes()).await?;
+            if !self.redact_body {
+                self.record(Direction::Client, &buf);
+            }
+            size += buf.len();
+            buf.clear();
+            if chunk.is_none() {
+                break;
+            }
+        }
+        if self.redact_body {
+            self.record(Direction::Note, &format!("message body, {} bytes", size));
+        }
+        Ok(size)
+    }
+
+    fn in_flight(
+        &self,
+        email: &Email,
+        (accepted, rejected): RecipientOutcome,
+        size: usize,
+    ) -> InFlight {
+        InFlight {
+            message_id: email.message_id().map(str::to_string),
+            accepted,
+            rejected,
+            size,
+            timings: self.timings,
+            started_at: self.started_at,
+            body_started_at: self.lap_at,
+            transcript_start: self.transcript_start,
+        }
+    }
+
+    async fn final_reply(
+        &mut self,
+        email_options: &EmailOptions,
+        in_flight: InFlight,
+    ) -> Result<SendResult, MailerError> {
+        self.phase = SmtpPhase::Data;
+        let reply = self.read_response().await?;
+        if !reply.is_positive() {
+            return Err(reply_error("Failed to send body", reply));
+        }
+        let now = now_millis();
+        let timings = SendTimings {
+            body_ms: now.saturating_sub(in_flight.body_started_at),
+            total_ms: now.saturating_sub(in_flight.started_at),
+            ..in_flight.timings
+        };
+        let result = SendResult {
+            queue_id: reply.queue_id(),
+            reply,
+            message_id: in_flight.message_id,
+            accepted: in_flight.accepted,
+            rejected: in_flight.rejected,
+            relay: self.host.clone(),
+            tls: self.tls_policy,
+            auth_mechanism: self.auth_mechanism,
+            size: in_flight.size,
+            timings,
+            transcript: self
+                .transcript
+                .as_ref()
+                .map(|t| t.since(in_flight.transcript_start)),
+        };
+        if let Some(ref f) = self.hooks.on_sent {
+            f(email_options, &result);
+        }
+        if let Some(hook) = self.hooks.on_sent_async.clone() {
+            hook.call((EmailSummary::from(email_options), result.clone()))
+                .await;
+        }
+        Ok(result)
+    }
+
+    async fn initialize_smtp_session(&mut self) -> Result<(), MailerError> {
+        self.greet().await?;
+        self.ehlo().await?;
+
+        let required = self.requested_tls == TlsPolicy::StartTlsRequired;
+        if required && !self.capabilities.start_tls {
+            return Err(MailerError::Tls("Server does not offer STARTTLS".into()));
+        }
+        if (required || self.requested_tls == TlsPolicy::StartTlsOpportunistic)
+            && self.capabilities.start_tls
+        {
+            self.upgrade_tls(required).await?;
+        }
+
+        self.phase = SmtpPhase::Auth;
+        self.auth().await?;
+        Ok(())
+    }
+
+    /// STARTTLS (RFC 3207). When not `required`, a refusal leaves the session in plaintext.
+    async fn upgrade_tls(&mut self, required: bool) -> Result<(), MailerError> {
+        self.phase = SmtpPhase::StartTls;
+        self.write_line("STARTTLS").await?;
+        let r = self.read_response().await?;
+        if r.code != 220 {
+            if required {
+                return Err(reply_error("STARTTLS failed", r));
+            }
+            self.logger
+                .warn(&format!("STARTTLS refused, continuing in plaintext: {}", r));
+            return Ok(());
+        }
+        // Anything buffered before the handshake must not be trusted afterwards.
+        self.replies.clear();
+        let host = self.host.clone();
+        let ms = self.socket_timeout_ms;
+        match timeout(ms, self.transport_mut()?.start_tls(&host)).await {
+            Some(result) => {
+                result.map_err(|e| MailerError::Tls(format!("STARTTLS failed: {}", e)))?
+            }
+            None => return Err(self.timed_out(ms).await),
+        }
+        self.tls_policy = self.requested_tls;
+        self.record(Direction::Note, "TLS established");
+        self.ehlo().await
+    }
+
+    fn transport_mut(&mut self) -> Result<&mut Box<dyn Transport>, MailerError> {
+        self.transport
+            .as_mut()
+            .ok_or_else(|| connection_error("Not connected"))
+    }
+
+    /// Reads and parses the next reply within `response_timeout_ms`.
+    async fn read_response(&mut self) -> Result<SmtpReply, MailerError> {
+        let ms = self.response_timeout_ms;
+        match timeout(ms, self.read_reply()).await {
+            Some(result) => result,
+            None => Err(self.timed_out(ms).await),
+        }
+    }
+
+    /// Drops the connection, whose state is unknown after a stall, and reports the phase.
+    async fn timed_out(&mut self, ms: u64) -> MailerError {
+        self.logger
+            .error(&format!("{} timed out after {} ms", self.phase, ms));
+        self.abort_connection().await;
+        SmtpTimeoutError::new(self.phase, ms).into()
+    }
+
+    /// Reads `count` replies to pipelined commands, in order.
+    async fn read_responses(&mut self, count: usize) -> Result<Vec<SmtpReply>, MailerError> {
+        let mut replies = Vec::with_capacity(count);
+        for _ in 0..count {
+            replies.push(self.read_response().await?);
+        }
+        Ok(replies)
+    }
+
+    async fn read_reply(&mut self) -> Result<SmtpReply, MailerError> {
+        let mut buf = vec![0u8; 4096];
+        let response = loop {
+            match self.replies.next_reply() {
+                Ok(Some(response)) => break response,
+                Ok(None) => {}
+                Err(e) => return Err(self.out_of_sync(e).await),
+            }
+            let n = self
+                .transport_mut()?
+                .read(&mut buf)
+                .await
+                .map_err(|e| connection_error(format!("read error: {}", e)))?;
+            if n > 0 {
+                self.replies.extend(&buf[..n]);
+                continue;
+            }
+            match self.replies.finish() {
+                Ok(Some(response)) => break response,
+                Ok(None) => return Err(connection_error("Connection closed by server")),
+                Err(e) => return Err(self.out_of_sync(e).await),
+            }
+        };
+        self.logger.debug(&format!("SMTP response:\n{}", response));
+        self.record(Direction::Server, &response);
+        SmtpReply::parse(&response).map_err(MailerError::Protocol)
+    }
+
+    /// A malformed reply leaves no way to tell where the next one starts.
+    async fn out_of_sync(&mut self, error: String) -> MailerError {
+        self.abort_connection().await;
+        MailerError::Protocol(error)
+    }
+
+    async fn write_line(&mut self, line: &str) -> Result<(), MailerError> {
+        self.write(&format!("{}\r\n", line)).await
+    }
+
+    /// Writes `data`, logging it and, once written, recording it with SASL payloads masked.
+    async fn write(&mut self, data: &str) -> Result<(), MailerError> {
+        let shown = match self.phase {
+            SmtpPhase::Auth => redact_auth(data),
+            _ => data.to_string(),
+        };
+        self.logger.debug(&format!("Write:\n{}", shown));
+        self.send_bytes(&encode(data)).await?;
+        self.record(Direction::Client, &shown);
+        Ok(())
+    }
+
+    async fn send_bytes(&mut self, bytes: &[u8]) -> Result<(), MailerError> {
+        let ms = self.socket_timeout_ms;
+        match timeout(ms, self.transport_mut()?.write(bytes)).await {
+            Some(result) => result.map_err(|e| connection_error(format!("write error: {}", e))),
+            None => Err(self.timed_out(ms).await),
+        }
+    }
+
+    async fn greet(&mut self) -> Result<(), MailerError> {
+        self.phase = SmtpPhase::Greeting;
+        let reply = self.read_response().await?;
+        if reply.code != 220 {
+            return Err(reply_error("Failed to connect", reply));
+        }
+        Ok(())
+    }
+
+    async fn ehlo(&mut self) -> Result<(), MailerError> {
+        self.phase = SmtpPhase::Ehlo;
+        self.write_line(&format!("EHLO {}", self.client_hostname))
+            .await?;
+        let reply = self.read_response().await?;
+        if reply.code == 421 {
+            return Err(reply_error("EHLO failed", reply));
+        }
+        if !reply.is_positive() {
+            self.capabilities = ServerCapabilities::default();
+            self.helo().await?;
+            return Ok(());
+        }
+        self.capabilities = ServerCapabilities::from_reply(&reply);
+        Ok(())
+    }
+
+    async fn helo(&mut self) -> Result<(), MailerError> {
+        self.write_line(&format!("HELO {}", self.client_hostname))
+            .await?;
+        let reply = self.read_response().await?;
+        if !reply.is_positive() {
+            return Err(reply_error("HELO failed", reply));
+        }
+        Ok(())
+    }
+
+    /// Picks the first of `preference` that is both configured and advertised.
+    fn pick_auth(&self, preference: &[AuthType]) -> Option<AuthType> {
+        preference
+            .iter()
+            .copied()
+            .find(|t| self.auth_type.contains(t) && self.capabilities.supports_auth(t.mechanism()))
+    }
+
+    async fn auth(&mut self) -> Result<(), MailerError> {
+        if self.capabilities.auth.is_empty() {
+            return Ok(());
+        }
+        if self.credentials.is_some()
+            && self.tls_policy == TlsPolicy::None
+            && !self.allow_plaintext_auth
+        {
+            return Err(MailerError::Tls(
+                "Refusing to authenticate over an unencrypted connection (see allow_plaintext_auth)".into(),
+            ));
+        }
+        let (username, password) = match self.credentials.clone() {
+            Some(Credentials::Password { username, password }) => (username, password),
+            Some(Credentials::OAuth2 {
+                username,
+                access_token,
+            }) => return self.auth_oauth(&username, access_token).await,
+            None => return Err(SmtpAuthError("Auth required but no credentials".into()).into()),
+        };
+
+        // SCRAM first: the password never crosses the wire.
+        let preference = [
+            AuthType::ScramSha256,
+            AuthType::ScramSha1,
+            AuthType::Plain,
+            AuthType::Login,
+            AuthType::CramMd5,
+        ];
+        self.auth_mechanism = self.pick_auth(&preference);
+        match self.auth_mechanism {
+            Some(AuthType::ScramSha256) => {
+                self.auth_scram(ScramHash::Sha256, &username, &password)
+                    .await
+            }
+            Some(AuthType::ScramSha1) => {
+                self.auth_scram(ScramHash::Sha1, &username, &password).await
+            }
+            Some(AuthType::Plain) => self.auth_plain(&username, &password).await,
+            Some(AuthType::Login) => self.auth_login(&username, &password).await,
+            Some(AuthType::CramMd5) => self.auth_cram_md5(&username, &password).await,
+            _ => Err(SmtpAuthError("No supported auth method".into()).into()),
+        }
+    }
+
+    async fn auth_plain(&mut self, username: &str, password: &str) -> Result<(), MailerError> {
+        let blob = format!("\u{0}{}\u{0}{}", username, password);
+        let b64 = B64.encode(blob.as_bytes());
+        self.write_line(&format!("AUTH PLAIN {}", b64)).await?;
+        let r = self.read_response().await?;
+        if !r.is_positive() {
+            return Err(auth_error("AUTH PLAIN failed", r));
+        }
+        Ok(())
+    }
+
+    async fn auth_login(&mut self, username: &str, password: &str) -> Result<(), MailerError> {
+        self.write_line("AUTH LOGIN").await?;
+        let r = self.read_response().await?;
+        if !r.is_intermediate() {
+            return Err(auth_error("AUTH LOGIN", r));
+        }
+        let u = B64.encode(username.as_bytes());
+        self.write_line(&u).await?;
+        let r = self.read_response().await?;
+        if !r.is_intermediate() {
+            return Err(auth_error("AUTH LOGIN user", r));
+        }
+        let p = B64.encode(password.as_bytes());
+        self.write_line(&p).await?;
+        let r = self.read_response().await?;
+        if !r.is_positive() {
+            return Err(auth_error("AUTH LOGIN", r));
+        }
+        Ok(())
+    }
+
+    async fn auth_cram_md5(&mut self, username: &str, password: &str) -> Result<(), MailerError> {
+        self.write_line("AUTH CRAM-MD5").await?;
+        let r = self.read_response().await?;
+        if r.code != 334 {
+            return Err(auth_error("AUTH CRAM-MD5", r));
+        }
+        let challenge = B64
+            .decode(r.text().trim())
+            .map_err(|_| MailerError::Protocol("Invalid CRAM-MD5 challenge".into()))?;
+        let response = cram_md5_response(username, password, &challenge);
+        self.write_line(&B64.encode(response.as_bytes())).await?;
+        let r = self.read_response().await?;
+        if r.code != 235 {
+            // 535: credentials rejected; anything else is a protocol error.
+            return Err(auth_error("AUTH CRAM-MD5 failed", r));
+        }
+        Ok(())
+    }
+
+    async fn auth_scram(
+        &mut self,
+        hash: ScramHash,
+        username: &str,
+        password: &str,
+    ) -> Result<(), MailerError> {
+        let name = hash.mechanism();
+        let mut scram = ScramClient::new(hash, username, password).map_err(SmtpAuthError)?;
+        self.write_line(&format!(
+            "AUTH {} {}",
+            name,
+            B64.encode(scram.client_first())
+        ))
+        .await?;
+        let server_first = self.read_challenge(name).await?;
+        let client_final = scram
+            .client_final(&server_first)
+            .map_err(|e| SmtpAuthError(format!("AUTH {} failed: {}", name, e)))?;
+        self.write_line(&B64.encode(client_final)).await?;
+        let server_final = self.read_challenge(name).await?;
+        scram
+            .verify_server_final(&server_final)
+            .map_err(|e| SmtpAuthError(format!("AUTH {} failed: {}", name, e)))?;
+        self.write_line("").await?;
+        let r = self.read_response().await?;
+        if r.code != 235 {
+            return Err(auth_error(format!("AUTH {} failed", name), r));
+        }
+        Ok(())
+    }
+
+    /// Reads a `334` reply and returns its base64-decoded payload.
+    async fn read_challenge(&mut self, mechanism: &str) -> Result<String, MailerError> {
+        let r = self.read_response().await?;
+        if r.code != 334 {
+            return Err(auth_error(format!("AUTH {} failed", mechanism), r));
+        }
+        let bytes = B64
+            .decode(r.text().trim())
+            .map_err(|_| MailerError::Protocol(format!("Invalid {} challenge", mechanism)))?;
+        Ok(String::from_utf8_lossy(&bytes).into_owned())
+    }
+
+    /// XOAUTH2/OAUTHBEARER. With a token provider, fetches a token when none is set and
+    /// refreshes it once if the server rejects the current one.
+    async fn auth_oauth(
+        &mut self,
+        username: &str,
+        access_token: String,
+    ) -> Result<(), MailerError> {
+        let Some(mechanism) = self.pick_auth(&[AuthType::OAuthBearer, AuthType::XOAuth2]) else {
+            return Err(SmtpAuthError("No supported auth method".into()).into());
+        };
+        self.auth_mechanism = Some(mechanism);
+
+        let mut token = access_token;
+        if token.is_empty() {
+            token = self.refresh_token(username).await?;
+        }
+        let error = match self.auth_bearer(mechanism, username, &token).await? {
+            None => return Ok(()),
+            Some(error) => error,
+        };
+        if self.token_provider.is_none() {
+            return Err(SmtpAuthError(format!("AUTH failed: {}", error)).into());
+        }
+        self.logger
+            .info(&format!("Access token rejected ({}), refreshing", error));
+        let token = self.refresh_token(username).await?;
+        match self.auth_bearer(mechanism, username, &token).await? {
+            None => Ok(()),
+            Some(error) => Err(SmtpAuthError(format!("AUTH failed: {}", error)).into()),
+        }
+    }
+
+    async fn refresh_token(&mut self, username: &str) -> Result<String, MailerError> {
+        let provider = self
+            .token_provider
+            .clone()
+            .ok_or_else(|| SmtpAuthError("No access token and no token provider".into()))?;
+        let token = provider
+            .fetch_token(username)
+            .await
+            .map_err(|e| SmtpAuthError(format!("Token refresh failed: {}", e)))?;
+        let credentials = Credentials::oauth2(username, token.clone());
+        // Kept on the endpoint too, so the next session starts with the fresh token.
+        self.endpoints[self.endpoint].credentials = Some(credentials.clone());
+        self.credentials = Some(credentials);
+        Ok(token)
+    }
+
+    /// One bearer-token attempt. `Ok(Some(message))` means the server rejected the token
+    /// with a 334 error payload (already acknowledged), so a retry may succeed.
+    async fn auth_bearer(
+        &mut self,
+        mechanism: AuthType,
+        username: &str,
+        token: &str,
+    ) -> Result<Option<String>, MailerError> {
+        let (name, initial, abort) = match mechanism {
+            AuthType::OAuthBearer => (
+                "OAUTHBEARER",
+                oauthbearer_initial_response(username, &self.host, self.port, token),
+                "AQ==",
+            ),
+            _ => ("XOAUTH2", xoauth2_initial_response(username, token), ""),
+        };
+        self.write_line(&format!("AUTH {} {}", name, B64.encode(initial.as_bytes())))
+            .await?;
+        let r = self.read_response().await?;
+        if r.code == 235 {
+            return Ok(None);
+        }
+        if r.code != 334 {
+            return Err(auth_error(format!("AUTH {} failed", name), r));
+        }
+        let message = oauth_error_message(&B64.decode(r.text().trim()).unwrap_or_default());
+        // The server waits for a dummy response before sending the final 535.
+        self.write_line(abort).await?;
+        self.read_response().await?;
+        Ok(Some(message))
+    }
+
+    /// DSN parameters for this message, `None` unless DSN was asked for (in the options
+    /// or on the email) and the server offers it.
+    fn dsn_params(&self, email: &Email) -> Option<DsnParams> {
+        if !self.capabilities.dsn || (self.dsn.is_none() && email.dsn_override.is_none()) {
+            return None;
+        }
+        Some(DsnParams::resolve(
+            self.dsn.as_ref(),
+            email.dsn_override.as_ref(),
+        ))
+    }
+
+    fn mail_command(&self, email: &Email) -> Result<String, MailerError> {
+        let mut msg = format!("MAIL FROM: <{}>", email.from.email);
+        if let Some(dsn) = self.dsn_params(email) {
+            if let Some(ret) = dsn.ret {
+                msg.push_str(&format!(" RET={}", ret));
+            }
+            if let Some(ref envid) = dsn.envelope_id {
+                msg.push_str(&format!(" ENVID={}", encode_xtext(envid)));
+            }
+        }
+        if let Some(hold) = self.hold_param(email)? {
+            msg.push(' ');
+            msg.push_str(&hold);
+        }
+        if let Some(priority) = email.priority {
+            if self.capabilities.mt_priority.is_some() {
+                msg.push_str(&format!(" MT-PRIORITY={}", priority));
+            } else {
+                self.logger
+                    .debug("MT-PRIORITY not offered, sending without priority");
+            }
+        }
+        if let Some(by) = email.deliver_by {
+            match self.capabilities.deliver_by {
+                // RFC 2852 section 4: a returned message needs at least the minimum time.
+                Some(min) if by.mode == DeliverByMode::Return && by.seconds < min as i64 => {
+                    return Err(MailerError::Unsupported(format!(
+                        "deliver-by of {} s is below the server minimum of {} s",
+                        by.seconds, min
+                    )));
+                }
+                Some(_) => msg.push_str(&format!(" BY={}", by)),
+                None => self
+                    .logger
+                    .debug("DELIVERBY not offered, sending without deadline"),
+            }
+        }
+        Ok(msg)
+    }
+
+    /// `HOLDFOR=` / `HOLDUNTIL=` (RFC 4865) for a held message, checked against the
+    /// server's limits. Without FUTURERELEASE, `hold_fallback` decides.
+    fn hold_param(&self, email: &Email) -> Result<Option<String>, MailerError> {
+        let now = now_millis();
+        let (param, until_ms) = match (email.hold_for, email.hold_until) {
+            (Some(secs), _) => {
+                let until_ms = now.saturating_add(secs.saturating_mul(1000));
+                (format!("HOLDFOR={}", secs), until_ms)
+            }
+            (None, Some(ms)) => (format!("HOLDUNTIL={}", format_rfc3339(ms)), ms),
+            (None, None) => return Ok(None),
+        };
+        let Some(limits) = self.capabilities.future_release else {
+            return match email.hold_fallback {
+                HoldFallback::SendNow => {
+                    self.logger
+                        .warn("FUTURERELEASE not offered, sending the held message now");
+                    Ok(None)
+                }
+                HoldFallback::Fail => Err(MailerError::Unsupported(format!(
+                    "{} does not offer FUTURERELEASE",
+                    self.host
+                ))),
+            };
+        };
+        let interval = until_ms.saturating_sub(now) / 1000;
+        if interval > limits.max_interval_secs {
+            return Err(MailerError::Unsupported(format!(
+                "hold of {} s exceeds the server maximum of {} s",
+                interval, limits.max_interval_secs
+            )));
+        }
+        if let Some(max) = limits.max_until_ms.filter(|&max| until_ms > max) {
+            return Err(MailerError::Unsupported(format!(
+                "hold until {} is past the server maximum of {}",
+                format_rfc3339(until_ms),
+                format_rfc3339(max)
+            )));
+        }
+        Ok(Some(param))
+    }
+
+    /// `(address, RCPT TO line)` for every To, Cc and Bcc recipient.
+    fn rcpt_commands(&self, email: &Email) -> Vec<(String, String)> {
+        let mut all = email.to.clone();
+        if let Some(ref cc) = email.cc {
+            all.extend(cc.iter().cloned());
+        }
+        if let Some(ref bcc) = email.bcc {
+            all.extend(bcc.iter().cloned());
+        }
+        let dsn = self.dsn_params(email);
+        all.into_iter()
+            .map(|user| {
+                let mut line = format!("RCPT TO: <{}>", user.email);
+                if let Some(ref dsn) = dsn {
+                    if let Some(ref notify) = dsn.notify {
+                        line.push_str(&format!(" NOTIFY={}", notify));
+                    }
+                    line.push_str(&format!(" ORCPT=rfc822;{}", encode_xtext(&user.email)));
+                }
+                (user.email, line)
+            })
+            .collect()
+    }
+
+    async fn cmd_mail(&mut self, email: &Email) -> Result<(), MailerError> {
+        self.phase = SmtpPhase::MailFrom;
+        let msg = self.mail_command(email)?;
+        self.write_line(&msg).await?;
+        let r = self.read_response().await?;
+        self.timings.mail_from_ms = self.lap();
+        if !r.is_positive() {
+            return Err(reply_error("MAIL FROM failed", r));
+        }
+        Ok(())
+    }
+
+    async fn cmd_rcpt(&mut self, email: &Email) -> Result<RecipientOutcome, MailerError> {
+        self.phase = SmtpPhase::RcptTo;
+        let mut accepted = Vec::new();
+        let mut rejected = Vec::new();
+        for (address, line) in self.rcpt_commands(email) {
+            self.write_line(&line).await?;
+            let reply = self.read_response().await?;
+            if reply.is_positive() {
+                accepted.push(AcceptedRecipient {
+                    recipient: address,
+                    reply,
+                });
+                continue;
+            }
+            let error = rejection(&address, reply);
+            self.emit_rejected(&error).await;
+            rejected.push(error);
+            if self.recipient_policy == RecipientPolicy::FailAll {
+                break;
+            }
+        }
+        self.timings.rcpt_to_ms = self.lap();
+        match self.recipient_failure(&accepted, &mut rejected) {
+            Some(error) => Err(error),
+            None => Ok((accepted, rejected)),
+        }
+    }
+
+    /// The rejection that fails the message under the recipient policy, if any.
+    fn recipient_failure(
+        &self,
+        accepted: &[AcceptedRecipient],
+        rejected: &mut Vec<SmtpRecipientError>,
+    ) -> Option<MailerError> {
+        if rejected.is_empty()
+            || (self.recipient_policy == RecipientPolicy::ContinueIfAnyAccepted
+                && !accepted.is_empty())
+        {
+            return None;
+        }
+        Some(rejected.remove(0).into())
+    }
+
+    /// RFC 2920: MAIL FROM, every RCPT TO and DATA in one write. Returns the recipients
+    /// whose replies [`read_envelope`](Self::read_envelope) reads back in order.
+    async fn write_envelope(&mut self, email: &Email) -> Result<Vec<String>, MailerError> {
+        let rcpts = self.rcpt_commands(email);
+        let mut batch = format!("{}\r\n", self.mail_command(email)?);
+        for (_, line) in &rcpts {
+            batch.push_str(line);
+            batch.push_str("\r\n");
+        }
+        batch.push_str("DATA\r\n");
+        self.phase = SmtpPhase::MailFrom;
+        self.write(&batch).await?;
+        Ok(rcpts.into_iter().map(|(address, _)| address).collect())
+    }
+
+    async fn read_envelope(&mut self, rcpts: Vec<String>) -> Result<RecipientOutcome, MailerError> {
+        self.phase = SmtpPhase::MailFrom;
+        // Every reply is read even after a failure, so the session stays in sync.
+        let mail = self.read_response().await?;
+        self.timings.mail_from_ms = self.lap();
+        let mut error = None;
+        if !mail.is_positive() {
+            error = Some(reply_error("MAIL FROM failed", mail));
+        }
+        self.phase = SmtpPhase::RcptTo;
+        let mut accepted = Vec::new();
+        let mut rejected = Vec::new();
+        let replies = self.read_responses(rcpts.len()).await?;
+        // After a failed MAIL FROM the RCPT replies only report the bad sequence (503).
+        if error.is_none() {
+            for (address, reply) in rcpts.into_iter().zip(replies) {
+                if reply.is_positive() {
+                    accepted.push(AcceptedRecipient {
+                        recipient: address,
+                        reply,
+                    });
+                } else {
+                    let error = rejection(&address, reply);
+                    self.emit_rejected(&error).await;
+                    rejected.push(error);
+                }
+            }
+        }
+        self.timings.rcpt_to_ms = self.lap();
+        if error.is_none() {
+            error = self.recipient_failure(&accepted, &mut rejected);
+        }
+        self.phase = SmtpPhase::Data;
+        let data = self.read_response().await?;
+        self.timings.data_ms = self.lap();
+        match error {
+            None if data.is_intermediate() => Ok((accepted, rejected)),
+            None => Err(reply_error("DATA failed", data)),
+            Some(error) => {
+                if data.is_intermediate() {
+                    // The server is waiting for a body that must not be delivered: closing
+                    // the connection is the only way to abandon the transaction.
+                    self.abort_connection().await;
+                }
+                Err(error)
+            }
+        }
+    }
+
+    async fn abort_connection(&mut self) {
+        self.replies.clear();
+        if let Some(mut t) = self.transport.take() {
+            let _ = t.close().await;
+        }
+    }
+
+    async fn cmd_data(&mut self) -> Result<(), MailerError> {
+        self.phase = SmtpPhase::Data;
+        self.write_line("DATA").await?;
+        let r = self.read_response().await?;
+        self.timings.data_ms = self.lap();
+        if !r.is_intermediate() {
+            return Err(reply_error("DATA failed", r));
         }
         Ok(())
     }
+
+    /// QUIT and wait for the goodbye, ignoring failures. Nothing to do without a session.
+    async fn quit(&mut self) {
+        if self.transport.is_none() {
+            return;
+        }
+        self.phase = SmtpPhase::Quit;
+        let _ = self.write_line("QUIT").await;
+        let _ = self.read_response().await;
+    }
+
+    /// Close the connection. `error`, if any, is what ended the session; it is handed to
+    /// the `on_close` hooks.
+    pub async fn close(&mut self, error: Option<MailerError>) -> Result<(), MailerError> {
+        self.quit().await;
+        let mut result = Ok(());
+        if let Some(mut t) = self.transport.take() {
+            result = t
+                .close()
+                .await
+                .map_err(|e| connection_error(format!("close error: {}", e)));
+        }
+        if let Err(ref e) = result {
+            self.emit_error(None, e).await;
+        }
+        if let Some(ref f) = self.hooks.on_close {
+            f(error.as_ref());
+        }
+        if let Some(hook) = self.hooks.on_close_async.clone() {
+            hook.call(error).await;
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::email::Recipient;
+    use crate::transport::{MemoryConnector, MemoryTransport};
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader, DuplexStream};
+
+    /// Scripted SMTP server: sends `greeting`, then for each `(expected prefix, reply)`
+    /// reads one command (or a whole DATA body for ".") and answers. Returns what it read.
+    async fn serve(stream: DuplexStream, greeting: &str, script: &[(&str, &str)]) -> Vec<String> {
+        serve_on(&mut BufReader::new(stream), greeting, script).await
+    }
+
+    async fn serve_on(
+        reader: &mut BufReader<DuplexStream>,
+        greeting: &str,
+        script: &[(&str, &str)],
+    ) -> Vec<String> {
+        reader
+            .get_mut()
+            .write_all(greeting.as_bytes())
+            .await
+            .unwrap();
+        let mut received = Vec::new();
+        for (expected, reply) in script {
+            let mut line = String::new();
+            reader.read_line(&mut line).await.unwrap();
+            if *expected == "." {
+                let mut body = String::new();
+                while line != ".\r\n" && !line.is_empty() {
+                    body.push_str(&line);
+                    line.clear();
+                    reader.read_line(&mut line).await.unwrap();
+                }
+                received.push(body);
+            } else {
+                assert!(
+                    line.starts_with(expected),
+                    "expected {:?}, got {:?}",
+                    expected,
+                    line
+                );
+                received.push(line);
+            }
+            reader.get_mut().write_all(reply.as_bytes()).await.unwrap();
+        }
+        received
+    }
+
+    fn options() -> WorkerMailerOptions {
+        WorkerMailerOptions {
+            host: "smtp.example.com".into(),
+            credentials: Some(Credentials::password("user", "pass")),
+            auth_type: vec![AuthType::Plain],
+            log_level: LogLevel::None,
+            ..Default::default()
+        }
+    }
+
+    fn email() -> EmailOptions {
+        EmailOptions {
+            from: Recipient::Email("from@example.com".into()),
+            to: vec![Recipient::Email("to@example.com".into())],
+            subject: "Hi".into(),
+            text: Some("Hello".into()),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn sends_over_memory_transport() {
+        let (client, server) = MemoryTransport::pair(64 * 1024);
+        let client_side = async {
+            let connector = Box::new(MemoryConnector::new(vec![client]));
+            let mut mailer = WorkerMailer::connect_with(options(), connector)
+                .await
+                .unwrap();
+            assert_eq!(mailer.tls_policy(), TlsPolicy::StartTlsOpportunistic);
+            let reply = mailer.send_one(email()).await.unwrap();
+            mailer.close(None).await.unwrap();
+            reply
+        };
+        let server_side = serve(
+            server,
+            "220 mx ESMTP\r\n",
+            &[
+                ("EHLO", "250-mx\r\n250-STARTTLS\r\n250 AUTH PLAIN LOGIN\r\n"),
+                ("STARTTLS", "220 go ahead\r\n"),
+                ("EHLO", "250-mx\r\n250 AUTH PLAIN LOGIN\r\n"),
+                ("AUTH PLAIN AHVzZXIAcGFzcw==", "235 ok\r\n"),
+                ("MAIL FROM: <from@example.com>", "250 ok\r\n"),
+                ("RCPT TO: <to@example.com>", "250 ok\r\n"),
+                ("DATA", "354 go\r\n"),
+                (".", "250 queued as ABC\r\n"),
+                ("QUIT", "221 bye\r\n"),
+            ],
+        );
+        let (reply, received) = tokio::join!(client_side, server_side);
+        assert_eq!(received[0], "EHLO [127.0.0.1]\r\n");
+        assert_eq!(reply.reply.code, 250);
+        assert_eq!(reply.reply.text(), "queued as ABC");
+        assert_eq!(reply.accepted[0].recipient, "to@example.com");
+        assert_eq!(reply.queue_id.as_deref(), Some("ABC"));
+        assert!(reply
+            .message_id
+            .as_deref()
+            .is_some_and(|id| id.ends_with("@example.com>")));
+        assert_eq!(reply.auth_mechanism, Some(AuthType::Plain));
+        assert_eq!(reply.tls, TlsPolicy::StartTlsOpportunistic);
+        assert!(received[7].contains("Subject: Hi\r\n"));
+        assert_eq!(reply.size, received[7].len() + ".\r\n".len());
+    }
+
+    #[tokio::test]
+    async fn transcript_redacts_auth_and_body() {
+        let (client, server) = MemoryTransport::pair(64 * 1024);
+        let client_side = async {
+            let opts = WorkerMailerOptions {
+                auth_type: vec![AuthType::Login],
+                allow_plaintext_auth: true,
+                transcript: Some(TranscriptOptions::default()),
+                ..options()
+            };
+            let connector = Box::new(MemoryConnector::new(vec![client]));
+            let mut mailer = WorkerMailer::connect_with(opts, connector).await.unwrap();
+            let result = mailer.send_one(email()).await.unwrap();
+            (result, mailer.take_transcript().unwrap())
+        };
+        let server_side = serve(
+            server,
+            "220 mx ESMTP\r\n",
+            &[
+                ("EHLO", "250-mx\r\n250 AUTH LOGIN\r\n"),
+                ("AUTH LOGIN", "334 VXNlcm5hbWU6\r\n"),
+                ("dXNlcg==", "334 UGFzc3dvcmQ6\r\n"),
+                ("cGFzcw==", "235 ok\r\n"),
+                ("MAIL FROM", "250 ok\r\n"),
+                ("RCPT TO", "250 ok\r\n"),
+                ("DATA", "354 go\r\n"),
+                (".", "250 queued\r\n"),
+            ],
+        );
+        let ((result, transcript), _) = tokio::join!(client_side, server_side);
+        let text = transcript.to_string();
+        assert!(!text.contains("cGFzcw=="), "{}", text);
+        assert!(text.contains("C: AUTH LOGIN\n"));
+        assert!(!text.contains("Subject: Hi"));
+        assert!(text.contains("-- message body, "));
+        let lines = result.transcript.unwrap().lines;
+        assert!(lines[0].text.starts_with("MAIL FROM"));
+        assert_eq!(lines.last().unwrap().text, "250 queued");
+    }
+
+    #[tokio::test]
+    async fn transcript_skips_quit_without_session() {
+        let (client, server) = MemoryTransport::pair(64 * 1024);
+        let client_side = async {
+            let opts = WorkerMailerOptions {
+                credentials: None,
+                transcript: Some(TranscriptOptions::default()),
+                ..options()
+            };
+            let connector = Box::new(MemoryConnector::new(vec![client]));
+            let mut mailer = WorkerMailer::connect_with(opts, connector).await.unwrap();
+            // The server ends the session and no new connection can be opened.
+            mailer.send_one(email()).await.unwrap_err();
+            mailer.close(None).await.unwrap();
+            mailer.take_transcript().unwrap()
+        };
+        let server_side = serve(
+            server,
+            "220 mx ESMTP\r\n",
+            &[
+                ("EHLO", "250 mx\r\n"),
+                ("MAIL FROM", "421 4.4.2 closing\r\n"),
+            ],
+        );
+        let (transcript, _) = tokio::join!(client_side, server_side);
+        let text = transcript.to_string();
+        assert!(text.contains("S: 421 4.4.2 closing"), "{}", text);
+        assert!(!text.contains("QUIT"), "{}", text);
+    }
+
+    #[tokio::test]
+    async fn refuses_plaintext_auth_and_missing_starttls() {
+        let (client, server) = MemoryTransport::pair(64 * 1024);
+        let connector = Box::new(MemoryConnector::new(vec![client]));
+        let script = [("EHLO", "250-mx\r\n250 AUTH PLAIN\r\n")];
+        let (result, _) = tokio::join!(
+            WorkerMailer::connect_with(options(), connector),
+            serve(server, "220 mx ESMTP\r\n", &script)
+        );
+        assert!(matches!(result, Err(MailerError::Tls(_))));
+
+        let (client, server) = MemoryTransport::pair(64 * 1024);
+        let connector = Box::new(MemoryConnector::new(vec![client]));
+        let opts = WorkerMailerOptions {
+            tls: Some(TlsPolicy::StartTlsRequired),
+            credentials: None,
+            ..options()
+        };
+        let script = [("EHLO", "250 mx\r\n")];
+        let (result, _) = tokio::join!(
+            WorkerMailer::connect_with(opts, connector),
+            serve(server, "220 mx ESMTP\r\n", &script)
+        );
+        assert!(matches!(result, Err(MailerError::Tls(_))));
+    }
+
+    #[tokio::test]
+    async fn xoauth2_refreshes_rejected_token() {
+        let (first, first_server) = MemoryTransport::pair(64 * 1024);
+        let (second, second_server) = MemoryTransport::pair(64 * 1024);
+        let client_side = async {
+            let opts = WorkerMailerOptions {
+                credentials: Some(Credentials::oauth2("u@example.com", "stale")),
+                auth_type: vec![AuthType::XOAuth2],
+                token_provider: Some(TokenProvider::new(|_| async { Ok("fresh".to_string()) })),
+                ..options()
+            };
+            let connector = Box::new(MemoryConnector::new(vec![first, second]));
+            let mut mailer = WorkerMailer::connect_with(opts, connector).await.unwrap();
+            mailer.send_one(email()).await.unwrap();
+        };
+        let session = [
+            ("EHLO", "250-mx\r\n250-STARTTLS\r\n250 AUTH XOAUTH2\r\n"),
+            ("STARTTLS", "220 go ahead\r\n"),
+            ("EHLO", "250-mx\r\n250 AUTH XOAUTH2\r\n"),
+        ];
+        let fresh = "AUTH XOAUTH2 dXNlcj11QGV4YW1wbGUuY29tAWF1dGg9QmVhcmVyIGZyZXNoAQE=";
+        let first_side = async {
+            let mut script = session.to_vec();
+            script.extend([
+                (
+                    "AUTH XOAUTH2 ",
+                    "334 eyJzdGF0dXMiOiI0MDEiLCJzY2hlbWVzIjoiYmVhcmVyIn0=\r\n",
+                ),
+                ("\r\n", "535 5.7.8 bad token\r\n"),
+                (fresh, "235 ok\r\n"),
+                ("MAIL FROM", "421 4.4.2 idle too long\r\n"),
+            ]);
+            serve(first_server, "220 mx ESMTP\r\n", &script).await
+        };
+        // The reconnect reuses the refreshed token instead of the stale one.
+        let second_side = async {
+            let mut script = session.to_vec();
+            script.extend([
+                (fresh, "235 ok\r\n"),
+                ("MAIL FROM", "250 ok\r\n"),
+                ("RCPT TO", "250 ok\r\n"),
+                ("DATA", "354 go\r\n"),
+                (".", "250 queued\r\n"),
+            ]);
+            serve(second_server, "220 mx ESMTP\r\n", &script).await
+        };
+        tokio::join!(client_side, first_side, second_side);
+    }
+
+    #[tokio::test]
+    async fn authenticates_with_cram_md5() {
+        let (client, server) = MemoryTransport::pair(64 * 1024);
+        let client_side = async {
+            let opts = WorkerMailerOptions {
+                credentials: Some(Credentials::password("tim", "tanstaaftanstaaf")),
+                auth_type: vec![AuthType::CramMd5],
+                ..options()
+            };
+            let connector = Box::new(MemoryConnector::new(vec![client]));
+            let mut mailer = WorkerMailer::connect_with(opts, connector).await.unwrap();
+            mailer.close(None).await.unwrap();
+        };
+        // RFC 2195 example exchange
+        let server_side = serve(
+            server,
+            "220 mx ESMTP\r\n",
+            &[
+                ("EHLO", "250-mx\r\n250-STARTTLS\r\n250 AUTH CRAM-MD5\r\n"),
+                ("STARTTLS", "220 go ahead\r\n"),
+                ("EHLO", "250-mx\r\n250 AUTH CRAM-MD5\r\n"),
+                (
+                    "AUTH CRAM-MD5\r\n",
+                    "334 PDE4OTYuNjk3MTcwOTUyQHBvc3RvZmZpY2UucmVzdG9uLm1jaS5uZXQ+\r\n",
+                ),
+                (
+                    "dGltIGI5MTNhNjAyYzdlZGE3YTQ5NWI0ZTZlNzMzNGQzODkw\r\n",
+                    "235 ok\r\n",
+                ),
+                ("QUIT", "221 bye\r\n"),
+            ],
+        );
+        tokio::join!(client_side, server_side);
+    }
+
+    #[tokio::test]
+    async fn authenticates_with_scram() {
+        use hmac::{Hmac, Mac};
+        use sha2::{Digest, Sha256};
+
+        fn hmac(key: &[u8], data: &[u8]) -> Vec<u8> {
+            let mut mac = Hmac::<Sha256>::new_from_slice(key).unwrap();
+            mac.update(data);
+            mac.finalize().into_bytes().to_vec()
+        }
+        let (client, server) = MemoryTransport::pair(64 * 1024);
+        let client_side = async {
+            let opts = WorkerMailerOptions {
+                auth_type: vec![AuthType::ScramSha256],
+                ..options()
+            };
+            let connector = Box::new(MemoryConnector::new(vec![client]));
+            let mut mailer = WorkerMailer::connect_with(opts, connector).await.unwrap();
+            mailer.close(None).await.unwrap();
+        };
+        // The client nonce is random, so the server works its replies out as it goes.
+        let server_side = async {
+            let mut reader = BufReader::new(server);
+            let script = [
+                (
+                    "EHLO",
+                    "250-mx\r\n250-STARTTLS\r\n250 AUTH SCRAM-SHA-256\r\n",
+                ),
+                ("STARTTLS", "220 go ahead\r\n"),
+                ("EHLO", "250-mx\r\n250 AUTH SCRAM-SHA-256\r\n"),
+            ];
+            serve_on(&mut reader, "220 mx ESMTP\r\n", &script).await;
+
+            let mut line = String::new();
+            reader.read_line(&mut line).await.unwrap();
+            let payload = line.strip_prefix("AUTH SCRAM-SHA-256 ").unwrap().trim_end();
+            let client_first = String::from_utf8(B64.decode(payload).unwrap()).unwrap();
+            let client_first_bare = client_first.strip_prefix("n,,").unwrap();
+            let nonce = client_first_bare.strip_prefix("n=user,r=").unwrap();
+            let server_first = format!("r={}srv,s={},i=1", nonce, B64.encode("salt"));
+            let challenge = format!("334 {}\r\n", B64.encode(&server_first));
+            reader
+                .get_mut()
+                .write_all(challenge.as_bytes())
+                .await
+                .unwrap();
+
+            // Hi() with one iteration is a single HMAC over salt || INT(1).
+            let salted = hmac(b"pass", b"salt\0\0\0\x01");
+            let without_proof = format!("c=biws,r={}srv", nonce);
+            let auth_message = format!("{},{},{}", client_first_bare, server_first, without_proof);
+            let client_key = hmac(&salted, b"Client Key");
+            let signature = hmac(&Sha256::digest(&client_key), auth_message.as_bytes());
+            let proof: Vec<u8> = client_key
+                .iter()
+                .zip(&signature)
+                .map(|(k, s)| k ^ s)
+                .collect();
+            line.clear();
+            reader.read_line(&mut line).await.unwrap();
+            let client_final = String::from_utf8(B64.decode(line.trim_end()).unwrap()).unwrap();
+            assert_eq!(
+                client_final,
+                format!("{},p={}", without_proof, B64.encode(proof))
+            );
+
+            let server_signature = hmac(&hmac(&salted, b"Server Key"), auth_message.as_bytes());
+            let server_final = format!("v={}", B64.encode(server_signature));
+            let challenge = format!("334 {}\r\n", B64.encode(server_final));
+            reader
+                .get_mut()
+                .write_all(challenge.as_bytes())
+                .await
+                .unwrap();
+            serve_on(
+                &mut reader,
+                "",
+                &[("\r\n", "235 ok\r\n"), ("QUIT", "221 bye\r\n")],
+            )
+            .await;
+        };
+        tokio::join!(client_side, server_side);
+    }
+
+    #[tokio::test]
+    async fn emits_dsn_parameters() {
+        let (client, server) = MemoryTransport::pair(64 * 1024);
+        let client_side = async {
+            let opts = WorkerMailerOptions {
+                credentials: None,
+                dsn: Some(DsnOptions {
+                    ret: Some(DsnRet {
+                        headers: Some(true),
+                        full: None,
+                    }),
+                    notify: Some(DsnNotify {
+                        delay: None,
+                        failure: Some(true),
+                        success: None,
+                    }),
+                }),
+                ..options()
+            };
+            let connector = Box::new(MemoryConnector::new(vec![client]));
+            let mut mailer = WorkerMailer::connect_with(opts, connector).await.unwrap();
+            let email = EmailOptions {
+                dsn_override: Some(DsnOverride {
+                    envelope_id: Some("id=1".into()),
+                    ret: None,
+                    notify: Some(crate::email::DsnNotify {
+                        delay: Some(true),
+                        failure: Some(true),
+                        success: Some(true),
+                    }),
+                }),
+                ..email()
+            };
+            mailer.send_one(email).await.unwrap();
+        };
+        let server_side = serve(
+            server,
+            "220 mx ESMTP\r\n",
+            &[
+                ("EHLO", "250-mx\r\n250 DSN\r\n"),
+                ("MAIL FROM: <from@example.com> RET=HDRS ENVID=id+3D1\r\n", "250 ok\r\n"),
+                (
+                    "RCPT TO: <to@example.com> NOTIFY=SUCCESS,FAILURE,DELAY ORCPT=rfc822;to@example.com\r\n",
+                    "250 ok\r\n",
+                ),
+                ("DATA", "354 go\r\n"),
+                (".", "250 ok\r\n"),
+            ],
+        );
+        tokio::join!(client_side, server_side);
+    }
+
+    #[tokio::test]
+    async fn omits_dsn_parameters_unless_requested() {
+        let (client, server) = MemoryTransport::pair(64 * 1024);
+        let client_side = async {
+            let opts = WorkerMailerOptions {
+                credentials: None,
+                ..options()
+            };
+            let connector = Box::new(MemoryConnector::new(vec![client]));
+            let mut mailer = WorkerMailer::connect_with(opts, connector).await.unwrap();
+            mailer.send_one(email()).await.unwrap();
+        };
+        let server_side = serve(
+            server,
+            "220 mx ESMTP\r\n",
+            &[
+                ("EHLO", "250-mx\r\n250 DSN\r\n"),
+                ("MAIL FROM: <from@example.com>\r\n", "250 ok\r\n"),
+                ("RCPT TO: <to@example.com>\r\n", "250 ok\r\n"),
+                ("DATA", "354 go\r\n"),
+                (".", "250 ok\r\n"),
+            ],
+        );
+        tokio::join!(client_side, server_side);
+    }
+
+    #[tokio::test]
+    async fn times_out_waiting_for_silent_server() {
+        let (client, server) = MemoryTransport::pair(64 * 1024);
+        let client_side = async {
+            let opts = WorkerMailerOptions {
+                credentials: None,
+                response_timeout_ms: 50,
+                ..options()
+            };
+            let connector = Box::new(MemoryConnector::new(vec![client]));
+            let mut mailer = WorkerMailer::connect_with(opts, connector).await.unwrap();
+            mailer.send_one(email()).await.unwrap_err()
+        };
+        // Never answers DATA; holds the pipe open until the client hangs up.
+        let server_side = async {
+            let mut reader = BufReader::new(server);
+            let script = [
+                ("EHLO", "250 mx\r\n"),
+                ("MAIL FROM", "250 ok\r\n"),
+                ("RCPT TO", "250 ok\r\n"),
+            ];
+            let received = serve_on(&mut reader, "220 mx ESMTP\r\n", &script).await;
+            let mut rest = String::new();
+            while reader.read_line(&mut rest).await.unwrap_or(0) > 0 {}
+            received
+        };
+        let (error, _) = tokio::join!(client_side, server_side);
+        let MailerError::Timeout(ref timeout) = error else {
+            panic!("expected a timeout, got {:?}", error);
+        };
+        assert_eq!(timeout.phase, SmtpPhase::Data);
+        // no body went out, so trying again cannot send it twice
+        assert!(error.retryable());
+    }
+
+    #[tokio::test]
+    async fn pipelined_mail_from_failure_rejects_no_recipient() {
+        let rejected = Rc::new(std::cell::Cell::new(0));
+        let (client, server) = MemoryTransport::pair(64 * 1024);
+        let client_side = async {
+            let counter = rejected.clone();
+            let opts = WorkerMailerOptions {
+                credentials: None,
+                hooks: WorkerMailerHooks {
+                    on_recipient_rejected: Some(Rc::new(move |_: &SmtpRecipientError| {
+                        counter.set(counter.get() + 1)
+                    })),
+                    ..Default::default()
+                },
+                ..options()
+            };
+            let connector = Box::new(MemoryConnector::new(vec![client]));
+            let mut mailer = WorkerMailer::connect_with(opts, connector).await.unwrap();
+            mailer.send_one(email()).await.unwrap_err()
+        };
+        let server_side = serve(
+            server,
+            "220 mx ESMTP\r\n",
+            &[
+                ("EHLO", "250-mx\r\n250 PIPELINING\r\n"),
+                ("MAIL FROM", "550 5.7.1 sender refused\r\n"),
+                ("RCPT TO", "503 5.5.1 bad sequence\r\n"),
+                ("DATA", "503 5.5.1 bad sequence\r\n"),
+                ("RSET", "250 ok\r\n"),
+            ],
+        );
+        let (error, _) = tokio::join!(client_side, server_side);
+        assert_eq!(error.reply().map(|r| r.code), Some(550));
+        assert_eq!(rejected.get(), 0);
+    }
+
+    #[tokio::test]
+    async fn continues_when_some_recipients_are_accepted() {
+        let (client, server) = MemoryTransport::pair(64 * 1024);
+        let client_side = async {
+            let opts = WorkerMailerOptions {
+                credentials: None,
+                recipient_policy: RecipientPolicy::ContinueIfAnyAccepted,
+                ..options()
+            };
+            let connector = Box::new(MemoryConnector::new(vec![client]));
+            let mut mailer = WorkerMailer::connect_with(opts, connector).await.unwrap();
+            let email = EmailOptions {
+                cc: Some(vec![Recipient::Email("gone@example.com".into())]),
+                ..email()
+            };
+            mailer.send_one(email).await.unwrap()
+        };
+        let server_side = serve(
+            server,
+            "220 mx ESMTP\r\n",
+            &[
+                ("EHLO", "250 mx\r\n"),
+                ("MAIL FROM", "250 ok\r\n"),
+                ("RCPT TO: <to@example.com>", "250 ok\r\n"),
+                ("RCPT TO: <gone@example.com>", "550 5.1.1 no such user\r\n"),
+                ("DATA", "354 go\r\n"),
+                (".", "250 ok\r\n"),
+            ],
+        );
+        let (result, _) = tokio::join!(client_side, server_side);
+        assert_eq!(result.accepted.len(), 1);
+        assert_eq!(result.rejected[0].recipient, "gone@example.com");
+        assert!(result.rejected[0]
+            .reply
+            .as_ref()
+            .is_some_and(|r| r.has_status("5.1.1")));
+    }
+
+    #[tokio::test]
+    async fn resets_after_failed_transaction() {
+        let (client, server) = MemoryTransport::pair(64 * 1024);
+        let client_side = async {
+            let opts = WorkerMailerOptions {
+                credentials: None,
+                ..options()
+            };
+            let connector = Box::new(MemoryConnector::new(vec![client]));
+            let mut mailer = WorkerMailer::connect_with(opts, connector).await.unwrap();
+            assert!(mailer.send_one(email()).await.is_err());
+            mailer.noop().await.unwrap();
+            mailer.send_one(email()).await.unwrap();
+        };
+        let server_side = serve(
+            server,
+            "220 mx ESMTP\r\n",
+            &[
+                ("EHLO", "250 mx\r\n"),
+                ("MAIL FROM", "250 ok\r\n"),
+                ("RCPT TO", "450 4.2.1 try later\r\n"),
+                ("RSET", "250 ok\r\n"),
+                ("NOOP", "250 ok\r\n"),
+                ("MAIL FROM", "250 ok\r\n"),
+                ("RCPT TO", "250 ok\r\n"),
+                ("DATA", "354 go\r\n"),
+                (".", "250 ok\r\n"),
+            ],
+        );
+        tokio::join!(client_side, server_side);
+    }
+
+    #[tokio::test]
+    async fn reconnects_when_server_closes_session() {
+        let (first, first_server) = MemoryTransport::pair(64 * 1024);
+        let (second, second_server) = MemoryTransport::pair(64 * 1024);
+        let client_side = async {
+            let connector = Box::new(MemoryConnector::new(vec![first, second]));
+            let mut mailer = WorkerMailer::connect_with(options(), connector)
+                .await
+                .unwrap();
+            mailer.send_one(email()).await.unwrap()
+        };
+        let session = [
+            ("EHLO", "250-mx\r\n250-STARTTLS\r\n250 AUTH PLAIN\r\n"),
+            ("STARTTLS", "220 go ahead\r\n"),
+            ("EHLO", "250-mx\r\n250 AUTH PLAIN\r\n"),
+            ("AUTH PLAIN", "235 ok\r\n"),
+        ];
+        let first_side = async {
+            let mut script = session.to_vec();
+            script.push(("MAIL FROM", "421 4.4.2 idle too long\r\n"));
+            serve(first_server, "220 mx ESMTP\r\n", &script).await
+        };
+        let second_side = async {
+            let mut script = session.to_vec();
+            script.extend([
+                ("MAIL FROM", "250 ok\r\n"),
+                ("RCPT TO", "250 ok\r\n"),
+                ("DATA", "354 go\r\n"),
+                (".", "250 queued\r\n"),
+            ]);
+            serve(second_server, "220 mx ESMTP\r\n", &script).await
+        };
+        let (result, _, _) = tokio::join!(client_side, first_side, second_side);
+        assert_eq!(result.reply.text(), "queued");
+    }
+
+    #[tokio::test]
+    async fn pipelines_envelope_and_reports_rejected_recipient() {
+        let (client, server) = MemoryTransport::pair(64 * 1024);
+        let client_side = async {
+            let opts = WorkerMailerOptions {
+                credentials: None,
+                ..options()
+            };
+            let connector = Box::new(MemoryConnector::new(vec![client]));
+            let mut mailer = WorkerMailer::connect_with(opts, connector).await.unwrap();
+            let email = EmailOptions {
+                cc: Some(vec![Recipient::Email("gone@example.com".into())]),
+                ..email()
+            };
+            mailer.send_one(email).await.unwrap_err()
+        };
+        let server_side = serve(
+            server,
+            "220 mx ESMTP\r\n",
+            &[
+                ("EHLO", "250-mx\r\n250 PIPELINING\r\n"),
+                ("MAIL FROM", "250 ok\r\n"),
+                ("RCPT TO: <to@example.com>", "250 ok\r\n"),
+                ("RCPT TO: <gone@example.com>", "550 5.1.1 no such user\r\n"),
+                ("DATA", "354 go\r\n"),
+            ],
+        );
+        let (error, _) = tokio::join!(client_side, server_side);
+        let MailerError::Recipient(ref rejected) = error else {
+            panic!("expected a recipient error, got {:?}", error);
+        };
+        assert_eq!(rejected.recipient, "gone@example.com");
+        assert!(error
+            .reply()
+            .is_some_and(|r| r.is_permanent() && r.has_status("5.1.1")));
+        assert_eq!(error.code(), "RECIPIENT_REJECTED");
+        assert!(!error.retryable());
+    }
+
+    #[tokio::test]
+    async fn send_many_overlaps_transactions_and_rotates_connections() {
+        let (first, first_server) = MemoryTransport::pair(64 * 1024);
+        let (second, second_server) = MemoryTransport::pair(64 * 1024);
+        let client_side = async {
+            let opts = WorkerMailerOptions {
+                credentials: None,
+                max_messages_per_connection: Some(2),
+                ..options()
+            };
+            let connector = Box::new(MemoryConnector::new(vec![first, second]));
+            let mut mailer = WorkerMailer::connect_with(opts, connector).await.unwrap();
+            let rejected = EmailOptions {
+                to: vec![Recipient::Email("gone@example.com".into())],
+                ..email()
+            };
+            let results = mailer.send_many(vec![email(), rejected, email()]).await;
+            mailer.close(None).await.unwrap();
+            results
+        };
+        let first_side = serve(
+            first_server,
+            "220 mx ESMTP\r\n",
+            &[
+                ("EHLO", "250-mx\r\n250 PIPELINING\r\n"),
+                ("MAIL FROM", "250 ok\r\n"),
+                ("RCPT TO", "250 ok\r\n"),
+                ("DATA", "354 go\r\n"),
+                // The next envelope is already on the wire when this reply is sent.
+                (".", "250 queued as 1\r\n"),
+                ("MAIL FROM", "250 ok\r\n"),
+                ("RCPT TO: <gone@example.com>", "550 5.1.1 no such user\r\n"),
+                ("DATA", "554 no valid recipients\r\n"),
+                ("RSET", "250 ok\r\n"),
+                ("QUIT", "221 bye\r\n"),
+            ],
+        );
+        let second_side = serve(
+            second_server,
+            "220 mx ESMTP\r\n",
+            &[
+                ("EHLO", "250-mx\r\n250 PIPELINING\r\n"),
+                ("MAIL FROM", "250 ok\r\n"),
+                ("RCPT TO", "250 ok\r\n"),
+                ("DATA", "354 go\r\n"),
+                (".", "250 queued as 3\r\n"),
+                ("QUIT", "221 bye\r\n"),
+            ],
+        );
+        let (results, _, _) = tokio::join!(client_side, first_side, second_side);
+        assert_eq!(results.len(), 3);
+        assert_eq!(results[0].as_ref().unwrap().reply.text(), "queued as 1");
+        assert!(matches!(results[1], Err(MailerError::Recipient(_))));
+        assert_eq!(results[2].as_ref().unwrap().reply.text(), "queued as 3");
+    }
+
+    #[tokio::test]
+    async fn fails_over_to_backup_relay() {
+        let (first, first_server) = MemoryTransport::pair(64 * 1024);
+        let (second, second_server) = MemoryTransport::pair(64 * 1024);
+        let client_side = async {
+            let opts = WorkerMailerOptions {
+                endpoints: vec![
+                    Endpoint {
+                        priority: 10,
+                        credentials: Some(Credentials::password("backup", "secret")),
+                        ..Endpoint::new("backup.example.com", 587)
+                    },
+                    Endpoint::new("primary.example.com", 587),
+                ],
+                ..options()
+            };
+            let connector = Box::new(MemoryConnector::new(vec![first, second]));
+            let mut mailer = WorkerMailer::connect_with(opts, connector).await.unwrap();
+            assert_eq!(mailer.relay(), "backup.example.com");
+            mailer.send_one(email()).await.unwrap()
+        };
+        let first_side = serve(first_server, "421 4.3.2 busy\r\n", &[]);
+        let second_side = serve(
+            second_server,
+            "220 mx ESMTP\r\n",
+            &[
+                ("EHLO", "250-mx\r\n250-STARTTLS\r\n250 AUTH PLAIN\r\n"),
+                ("STARTTLS", "220 go ahead\r\n"),
+                ("EHLO", "250-mx\r\n250 AUTH PLAIN\r\n"),
+                ("AUTH PLAIN AGJhY2t1cABzZWNyZXQ=", "235 ok\r\n"),
+                ("MAIL FROM", "250 ok\r\n"),
+                ("RCPT TO", "250 ok\r\n"),
+                ("DATA", "354 go\r\n"),
+                (".", "250 queued\r\n"),
+            ],
+        );
+        let (result, _, _) = tokio::join!(client_side, first_side, second_side);
+        assert_eq!(result.relay, "backup.example.com");
+    }
+
+    #[tokio::test]
+    async fn retries_transient_failure() {
+        let (client, server) = MemoryTransport::pair(64 * 1024);
+        let client_side = async {
+            let opts = WorkerMailerOptions {
+                credentials: None,
+                ..options()
+            };
+            let connector = Box::new(MemoryConnector::new(vec![client]));
+            let mut mailer = WorkerMailer::connect_with(opts, connector).await.unwrap();
+            let policy = RetryPolicy {
+                initial_backoff_ms: 1,
+                ..Default::default()
+            };
+            // the attachment lent to the failed attempt is back for the next one
+            let email = EmailOptions {
+                attachments: Some(vec![crate::email::Attachment {
+                    filename: "a.txt".into(),
+                    content: "QUJD".into(),
+                    mime_type: None,
+                    cid: None,
+                    inline: None,
+                }]),
+                ..email()
+            };
+            mailer.send_one_with_retry(email, &policy).await.unwrap()
+        };
+        let server_side = serve(
+            server,
+            "220 mx ESMTP\r\n",
+            &[
+                ("EHLO", "250 mx\r\n"),
+                ("MAIL FROM", "451 4.3.0 try later\r\n"),
+                ("RSET", "250 ok\r\n"),
+                ("MAIL FROM", "250 ok\r\n"),
+                ("RCPT TO", "250 ok\r\n"),
+                ("DATA", "354 go\r\n"),
+                (".", "250 queued\r\n"),
+            ],
+        );
+        let (result, received) = tokio::join!(client_side, server_side);
+        assert_eq!(result.reply.text(), "queued");
+        assert!(received[6].contains("QUJD\r\n"));
+    }
+
+    #[tokio::test]
+    async fn does_not_retry_once_the_body_was_sent() {
+        let (client, server) = MemoryTransport::pair(64 * 1024);
+        let client_side = async {
+            let opts = WorkerMailerOptions {
+                credentials: None,
+                ..options()
+            };
+            let connector = Box::new(MemoryConnector::new(vec![client]));
+            let mut mailer = WorkerMailer::connect_with(opts, connector).await.unwrap();
+            let policy = RetryPolicy {
+                initial_backoff_ms: 1,
+                ..Default::default()
+            };
+            mailer
+                .send_one_with_retry(email(), &policy)
+                .await
+                .unwrap_err()
+        };
+        // Hangs up after the body, before the final reply.
+        let server_side = serve(
+            server,
+            "220 mx ESMTP\r\n",
+            &[
+                ("EHLO", "250 mx\r\n"),
+                ("MAIL FROM", "250 ok\r\n"),
+                ("RCPT TO", "250 ok\r\n"),
+                ("DATA", "354 go\r\n"),
+                (".", ""),
+            ],
+        );
+        let (error, _) = tokio::join!(client_side, server_side);
+        assert!(matches!(error, MailerError::Unconfirmed(_)), "{:?}", error);
+        assert!(!error.retryable());
+    }
+
+    #[tokio::test]
+    async fn hooks_edit_veto_and_observe_sends() {
+        use std::cell::RefCell;
+
+        let events = Rc::new(RefCell::new(Vec::<String>::new()));
+        let log = |events: &Rc<RefCell<Vec<String>>>| {
+            let events = events.clone();
+            move |event: String| events.borrow_mut().push(event)
+        };
+        let (push, push_async) = (log(&events), log(&events));
+        let (push_rejected, push_error, push_close) = (log(&events), log(&events), log(&events));
+        let hooks = WorkerMailerHooks {
+            before_send: Some(Rc::new(|email: &mut EmailOptions| {
+                if email.subject == "spam" {
+                    return Err("blocked".into());
+                }
+                email.subject = format!("[ok] {}", email.subject);
+                Ok(())
+            })),
+            on_recipient_rejected: Some(Rc::new(move |e: &SmtpRecipientError| {
+                push_rejected(format!("rejected {}", e.recipient))
+            })),
+            on_sent: Some(Rc::new(move |email: &EmailOptions, _: &SendResult| {
+                push(format!("sent {}", email.subject))
+            })),
+            on_sent_async: Some(AsyncHook::new(
+                move |(_, result): (EmailSummary, SendResult)| {
+                    let push_async = push_async.clone();
+                    async move { push_async(format!("stored {}", result.reply.text())) }
+                },
+            )),
+            on_error: Some(Rc::new(move |_: Option<&EmailSummary>, e: &MailerError| {
+                push_error(format!("error {}", e.code()))
+            })),
+            on_close: Some(Rc::new(move |e: Option<&MailerError>| {
+                push_close(format!("close {}", e.is_some()))
+            })),
+            ..Default::default()
+        };
+        let (client, server) = MemoryTransport::pair(64 * 1024);
+        let client_side = async {
+            let opts = WorkerMailerOptions {
+                credentials: None,
+                recipient_policy: RecipientPolicy::ContinueIfAnyAccepted,
+                // hooks survive cloning the options
+                hooks: hooks.clone(),
+                ..options()
+            };
+            let connector = Box::new(MemoryConnector::new(vec![client]));
+            let mut mailer = WorkerMailer::connect_with(opts, connector).await.unwrap();
+            let vetoed = EmailOptions {
+                subject: "spam".into(),
+                ..email()
+            };
+            let err = mailer.send_one(vetoed).await.unwrap_err();
+            assert!(matches!(err, MailerError::Vetoed(ref reason) if reason == "blocked"));
+            let email = EmailOptions {
+                cc: Some(vec![Recipient::Email("gone@example.com".into())]),
+                ..email()
+            };
+            mailer.send_one(email).await.unwrap();
+            mailer.close(None).await.unwrap();
+        };
+        let server_side = serve(
+            server,
+            "220 mx ESMTP\r\n",
+            &[
+                ("EHLO", "250 mx\r\n"),
+                ("MAIL FROM", "250 ok\r\n"),
+                ("RCPT TO: <to@example.com>", "250 ok\r\n"),
+                ("RCPT TO: <gone@example.com>", "550 5.1.1 no such user\r\n"),
+                ("DATA", "354 go\r\n"),
+                (".", "250 queued\r\n"),
+                ("QUIT", "221 bye\r\n"),
+            ],
+        );
+        let (_, received) = tokio::join!(client_side, server_side);
+        assert!(received[5].contains("Subject: [ok] Hi"));
+        assert_eq!(
+            *events.borrow(),
+            [
+                "error VETOED",
+                "rejected gone@example.com",
+                "sent [ok] Hi",
+                "stored queued",
+                "close false",
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn verifies_recipients_without_sending() {
+        let (client, server) = MemoryTransport::pair(64 * 1024);
+        let client_side = async {
+            let opts = WorkerMailerOptions {
+                credentials: None,
+                ..options()
+            };
+            let connector = Box::new(MemoryConnector::new(vec![client]));
+            let mut mailer = WorkerMailer::connect_with(opts, connector).await.unwrap();
+            let addresses = [
+                "ok@example.com",
+                "gone@example.com",
+                "not an address",
+                "full@example.com",
+                "slow@example.com",
+                "later@example.com",
+            ];
+            mailer
+                .verify_recipients("from@example.com", &addresses)
+                .await
+                .unwrap()
+        };
+        let server_side = serve(
+            server,
+            "220 mx ESMTP\r\n",
+            &[
+                ("EHLO", "250 mx\r\n"),
+                ("MAIL FROM: <from@example.com>", "250 ok\r\n"),
+                ("RCPT TO: <ok@example.com>", "250 ok\r\n"),
+                ("RCPT TO: <gone@example.com>", "550 5.1.1 no such user\r\n"),
+                (
+                    "RCPT TO: <full@example.com>",
+                    "452 4.5.3 too many recipients\r\n",
+                ),
+                ("RSET", "250 ok\r\n"),
+                ("MAIL FROM: <from@example.com>", "250 ok\r\n"),
+                ("RCPT TO: <full@example.com>", "250 ok\r\n"),
+                ("RCPT TO: <slow@example.com>", "450 4.7.1 rate limited\r\n"),
+                ("RSET", "250 ok\r\n"),
+            ],
+        );
+        let (verdicts, received) = tokio::join!(client_side, server_side);
+        assert!(!received.iter().any(|line| line.starts_with("DATA")));
+        let summary: Vec<_> = verdicts
+            .iter()
+            .map(|v| (v.address.as_str(), v.verdict))
+            .collect();
+        assert_eq!(
+            summary,
+            [
+                ("ok@example.com", RecipientVerdict::Accepted),
+                ("gone@example.com", RecipientVerdict::Rejected),
+                ("not an address", RecipientVerdict::Rejected),
+                ("full@example.com", RecipientVerdict::Accepted),
+                ("slow@example.com", RecipientVerdict::Deferred),
+                ("later@example.com", RecipientVerdict::Deferred),
+            ]
+        );
+        assert!(verdicts[5]
+            .reply
+            .as_ref()
+            .is_some_and(|r| r.has_status("4.7.1")));
+    }
+
+    #[tokio::test]
+    async fn failed_verification_reaches_on_error() {
+        let errors = Rc::new(std::cell::RefCell::new(Vec::new()));
+        let (client, server) = MemoryTransport::pair(64 * 1024);
+        let client_side = async {
+            let seen = errors.clone();
+            let opts = WorkerMailerOptions {
+                credentials: None,
+                hooks: WorkerMailerHooks {
+                    on_error: Some(Rc::new(move |_: Option<&EmailSummary>, e: &MailerError| {
+                        seen.borrow_mut().push(e.code())
+                    })),
+                    ..Default::default()
+                },
+                ..options()
+            };
+            let connector = Box::new(MemoryConnector::new(vec![client]));
+            let mut mailer = WorkerMailer::connect_with(opts, connector).await.unwrap();
+            mailer
+                .verify_recipients("from@example.com", &["to@example.com"])
+                .await
+                .unwrap_err();
+            mailer.noop().await.unwrap_err();
+        };
+        let server_side = serve(
+            server,
+            "220 mx ESMTP\r\n",
+            &[
+                ("EHLO", "250 mx\r\n"),
+                ("MAIL FROM", "550 5.7.1 sender refused\r\n"),
+                ("RSET", "250 ok\r\n"),
+                ("NOOP", "500 5.5.1 what\r\n"),
+            ],
+        );
+        tokio::join!(client_side, server_side);
+        assert_eq!(*errors.borrow(), ["UNEXPECTED_REPLY", "UNEXPECTED_REPLY"]);
+    }
+
+    #[tokio::test]
+    async fn verify_rejects_invalid_sender() {
+        let connector = Box::new(MemoryConnector::new(vec![]));
+        let mut mailer = WorkerMailer::new(options(), connector).unwrap();
+        let from = "from@example.com>\r\nRCPT TO: <x@example.com";
+        let error = mailer
+            .verify_recipients(from, &["to@example.com"])
+            .await
+            .unwrap_err();
+        assert!(matches!(error, MailerError::InvalidEmail(_)), "{:?}", error);
+    }
+
+    #[tokio::test]
+    async fn holds_message_for_future_release() {
+        let (client, server) = MemoryTransport::pair(64 * 1024);
+        let (plain_client, plain_server) = MemoryTransport::pair(64 * 1024);
+        let opts = || WorkerMailerOptions {
+            credentials: None,
+            ..options()
+        };
+        let held = |hold_for, hold_fallback| EmailOptions {
+            hold_for: Some(hold_for),
+            hold_fallback,
+            ..email()
+        };
+        let client_side = async {
+            let connector = Box::new(MemoryConnector::new(vec![client]));
+            let mut mailer = WorkerMailer::connect_with(opts(), connector).await.unwrap();
+            mailer
+                .send_one(held(3_600, HoldFallback::Fail))
+                .await
+                .unwrap();
+            let err = mailer
+                .send_one(held(999_999, HoldFallback::Fail))
+                .await
+                .unwrap_err();
+            assert_eq!(err.code(), "UNSUPPORTED");
+            let err = mailer
+                .send_one(held(u64::MAX / 1000, HoldFallback::Fail))
+                .await
+                .unwrap_err();
+            assert_eq!(err.code(), "UNSUPPORTED");
+
+            let connector = Box::new(MemoryConnector::new(vec![plain_client]));
+            let mut mailer = WorkerMailer::connect_with(opts(), connector).await.unwrap();
+            let err = mailer
+                .send_one(held(60, HoldFallback::Fail))
+                .await
+                .unwrap_err();
+            assert!(matches!(err, MailerError::Unsupported(_)));
+            mailer
+                .send_one(held(60, HoldFallback::SendNow))
+                .await
+                .unwrap();
+        };
+        let server_side = serve(
+            server,
+            "220 mx ESMTP\r\n",
+            &[
+                (
+                    "EHLO",
+                    "250-mx\r\n250 FUTURERELEASE 604800 2099-01-01T00:00:00Z\r\n",
+                ),
+                (
+                    "MAIL FROM: <from@example.com> HOLDFOR=3600\r\n",
+                    "250 ok\r\n",
+                ),
+                ("RCPT TO", "250 ok\r\n"),
+                ("DATA", "354 go\r\n"),
+                (".", "250 held\r\n"),
+            ],
+        );
+        let plain_server_side = serve(
+            plain_server,
+            "220 mx ESMTP\r\n",
+            &[
+                ("EHLO", "250 mx\r\n"),
+                ("MAIL FROM: <from@example.com>\r\n", "250 ok\r\n"),
+                ("RCPT TO", "250 ok\r\n"),
+                ("DATA", "354 go\r\n"),
+                (".", "250 queued\r\n"),
+            ],
+        );
+        tokio::join!(client_side, server_side, plain_server_side);
+    }
+
+    #[tokio::test]
+    async fn emits_priority_and_deliver_by_when_advertised() {
+        let (client, server) = MemoryTransport::pair(64 * 1024);
+        let client_side = async {
+            let opts = WorkerMailerOptions {
+                credentials: None,
+                ..options()
+            };
+            let connector = Box::new(MemoryConnector::new(vec![client]));
+            let mut mailer = WorkerMailer::connect_with(opts, connector).await.unwrap();
+            let urgent = |seconds| EmailOptions {
+                priority: Some(7),
+                deliver_by: Some(crate::email::DeliverBy {
+                    seconds,
+                    mode: DeliverByMode::Return,
+                    trace: false,
+                }),
+                ..email()
+            };
+            mailer.send_one(urgent(600)).await.unwrap();
+            let err = mailer.send_one(urgent(60)).await.unwrap_err();
+            assert_eq!(err.code(), "UNSUPPORTED");
+            let bad = EmailOptions {
+                priority: Some(10),
+                ..email()
+            };
+            assert_eq!(
+                mailer.send_one(bad).await.unwrap_err().code(),
+                "INVALID_CONTENT"
+            );
+        };
+        let server_side = serve(
+            server,
+            "220 mx ESMTP\r\n",
+            &[
+                (
+                    "EHLO",
+                    "250-mx\r\n250-MT-PRIORITY MIXER\r\n250 DELIVERBY 120\r\n",
+                ),
+                (
+                    "MAIL FROM: <from@example.com> MT-PRIORITY=7 BY=600;R\r\n",
+                    "250 ok\r\n",
+                ),
+                ("RCPT TO", "250 ok\r\n"),
+                ("DATA", "354 go\r\n"),
+                (".", "250 queued\r\n"),
+            ],
+        );
+        tokio::join!(client_side, server_side);
+    }
 }
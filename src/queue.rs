@@ -2,7 +2,11 @@
 
 use crate::email::EmailOptions;
 use crate::mailer::{WorkerMailer, WorkerMailerOptions};
-use worker::{MessageBatch, MessageExt};
+use crate::utils::now_millis;
+use worker::{MessageBatch, MessageExt, QueueRetryOptionsBuilder};
+
+/// Longest delay a queue message can be retried with (12 hours).
+const MAX_QUEUE_DELAY_SECONDS: u64 = 43_200;
 
 /// Message format for the email queue.
 #[derive(Clone, serde::Serialize, serde::Deserialize)]
@@ -16,32 +20,53 @@ pub struct QueueEmailMessage {
 pub struct QueueProcessResult {
     pub success: bool,
     pub error: Option<String>,
+    /// The failure was transient and the message went back to the queue.
+    pub retried: bool,
     pub email_options: EmailOptions,
 }
 
-/// Process a message batch and return results.
-pub async fn process_batch(
-    batch: MessageBatch<QueueEmailMessage>,
-) -> Vec<QueueProcessResult> {
+/// Process a message batch and return results. Each email is sent under its
+/// `mailer_options.retry` policy. When that gives up, transient failures go back to the
+/// queue after one more backoff step and permanent ones (e.g. 550) are acknowledged, as
+/// is any failure past the policy's `deadline_ms`, counted from when it was enqueued.
+pub async fn process_batch(batch: MessageBatch<QueueEmailMessage>) -> Vec<QueueProcessResult> {
     let messages = batch.messages().unwrap_or_default();
     let mut results = Vec::new();
     for message in messages {
         let mailer_options = message.body().mailer_options.clone();
         let email_options = message.body().email_options.clone();
+        let policy = mailer_options.retry.clone().unwrap_or_default();
         match WorkerMailer::send(mailer_options, email_options.clone()).await {
             Ok(_) => {
                 message.ack();
                 results.push(QueueProcessResult {
                     success: true,
                     error: None,
+                    retried: false,
                     email_options,
                 });
             }
             Err(e) => {
-                message.retry();
+                let age_ms = now_millis().saturating_sub(message.timestamp().as_millis());
+                let retried =
+                    policy.is_retryable(&e) && policy.deadline_ms.is_none_or(|d| age_ms < d);
+                if retried {
+                    let delay = policy
+                        .backoff_ms(policy.max_attempts)
+                        .div_ceil(1000)
+                        .min(MAX_QUEUE_DELAY_SECONDS) as u32;
+                    message.retry_with_options(
+                        &QueueRetryOptionsBuilder::new()
+                            .with_delay_seconds(delay)
+                            .build(),
+                    );
+                } else {
+                    message.ack();
+                }
                 results.push(QueueProcessResult {
                     success: false,
                     error: Some(e.to_string()),
+                    retried,
                     email_options,
                 });
             }
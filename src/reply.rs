@@ -0,0 +1,228 @@
+//! SMTP replies (RFC 5321 section 4.2) with RFC 3463 enhanced status codes.
+
+use std::fmt;
+
+/// Enhanced mail system status code `class.subject.detail` (e.g. `5.1.1`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, serde::Serialize, serde::Deserialize)]
+pub struct EnhancedStatus {
+    pub class: u8,
+    pub subject: u16,
+    pub detail: u16,
+}
+
+impl EnhancedStatus {
+    /// Parse `2.1.5`-style text; the class must be 2, 4 or 5.
+    pub fn parse(s: &str) -> Option<Self> {
+        let mut parts = s.split('.');
+        let class: u8 = parts.next()?.parse().ok()?;
+        let subject: u16 = parts.next()?.parse().ok()?;
+        let detail: u16 = parts.next()?.parse().ok()?;
+        if parts.next().is_some() || !matches!(class, 2 | 4 | 5) || subject > 999 || detail > 999 {
+            return None;
+        }
+        Some(Self {
+            class,
+            subject,
+            detail,
+        })
+    }
+}
+
+impl fmt::Display for EnhancedStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}.{}", self.class, self.subject, self.detail)
+    }
+}
+
+/// One complete (possibly multi-line) server reply.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct SmtpReply {
+    /// Three-digit reply code.
+    pub code: u16,
+    /// Enhanced status from the first line, when the server sends one.
+    pub enhanced: Option<EnhancedStatus>,
+    /// Text of each line, without the code and enhanced status.
+    pub lines: Vec<String>,
+}
+
+impl SmtpReply {
+    /// Parse a raw reply (`250-first\r\n250 last\r\n`).
+    pub fn parse(raw: &str) -> Result<Self, String> {
+        let mut code = None;
+        let mut lines = Vec::new();
+        for line in raw.lines().filter(|l| !l.is_empty()) {
+            let c = line
+                .get(..3)
+                .filter(|c| c.bytes().all(|b| b.is_ascii_digit()))
+                .and_then(|c| c.parse::<u16>().ok())
+                .filter(|c| (200..600).contains(c))
+                .ok_or_else(|| format!("Invalid SMTP reply: {}", raw.trim_end()))?;
+            code.get_or_insert(c);
+            lines.push(line.get(4..).unwrap_or("").to_string());
+        }
+        let code = code.ok_or_else(|| "Empty SMTP reply".to_string())?;
+
+        // An enhanced status is only meaningful when its class matches the reply code.
+        let enhanced = lines
+            .first()
+            .and_then(|l| l.split(' ').next())
+            .and_then(EnhancedStatus::parse)
+            .filter(|e| u16::from(e.class) == code / 100);
+        if let Some(e) = enhanced {
+            let prefix = e.to_string();
+            for line in &mut lines {
+                if let Some(rest) = line.strip_prefix(prefix.as_str()) {
+                    *line = rest.trim_start().to_string();
+                }
+            }
+        }
+        Ok(Self {
+            code,
+            enhanced,
+            lines,
+        })
+    }
+
+    /// 2xx: the command succeeded.
+    pub fn is_positive(&self) -> bool {
+        self.code / 100 == 2
+    }
+
+    /// 3xx: the server waits for more input (DATA, AUTH challenges).
+    pub fn is_intermediate(&self) -> bool {
+        self.code / 100 == 3
+    }
+
+    /// 4xx: temporary failure, the same command may succeed later.
+    pub fn is_transient(&self) -> bool {
+        self.code / 100 == 4
+    }
+
+    /// 5xx: permanent failure.
+    pub fn is_permanent(&self) -> bool {
+        self.code / 100 == 5
+    }
+
+    /// Queue id from a final 250 reply, in the common formats: `queued as ID` (Postfix),
+    /// `id=ID` (Exim), `ID Message accepted for delivery` (Sendmail), `Ok ID` (Amazon
+    /// SES) and `OK <time> ID - gsmtp` (Gmail).
+    pub fn queue_id(&self) -> Option<String> {
+        if !self.is_positive() {
+            return None;
+        }
+        let text = self.text();
+        let words: Vec<&str> = text.split_whitespace().collect();
+        let clean = |w: &str| {
+            Some(
+                w.trim_matches(|c: char| "<>()[],;.".contains(c))
+                    .to_string(),
+            )
+            .filter(|w| !w.is_empty())
+        };
+        for (i, word) in words.iter().enumerate() {
+            if word.eq_ignore_ascii_case("as")
+                && i > 0
+                && words[i - 1].eq_ignore_ascii_case("queued")
+            {
+                return words.get(i + 1).and_then(|w| clean(w));
+            }
+            if let Some(id) = word.strip_prefix("id=") {
+                return clean(id);
+            }
+        }
+        match words.as_slice() {
+            [.., id, "-", "gsmtp"] => clean(id),
+            [id, "Message", "accepted", ..] => clean(id),
+            [ok, id] if ok.eq_ignore_ascii_case("ok") => clean(id),
+            _ => None,
+        }
+    }
+
+    /// All text lines joined with `\n`.
+    pub fn text(&self) -> String {
+        self.lines.join("\n")
+    }
+
+    /// Whether the enhanced status equals `status` (e.g. `"5.1.1"`).
+    pub fn has_status(&self, status: &str) -> bool {
+        self.enhanced.is_some_and(|e| e.to_string() == status)
+    }
+}
+
+impl fmt::Display for SmtpReply {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.code)?;
+        if let Some(e) = self.enhanced {
+            write!(f, " {}", e)?;
+        }
+        let text = self.lines.join(" ");
+        if !text.is_empty() {
+            write!(f, " {}", text)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_multiline_with_enhanced_status() {
+        let reply = SmtpReply::parse(
+            "550-5.1.1 The email account does not exist.\r\n550 5.1.1 Try again.\r\n",
+        )
+        .unwrap();
+        assert_eq!(reply.code, 550);
+        assert!(reply.has_status("5.1.1"));
+        assert!(reply.is_permanent() && !reply.is_transient());
+        assert_eq!(
+            reply.lines,
+            vec!["The email account does not exist.", "Try again."]
+        );
+        assert_eq!(
+            reply.to_string(),
+            "550 5.1.1 The email account does not exist. Try again."
+        );
+    }
+
+    #[test]
+    fn test_parse_without_enhanced_status() {
+        let reply = SmtpReply::parse("354 End data with <CR><LF>.<CR><LF>\r\n").unwrap();
+        assert!(reply.is_intermediate());
+        assert_eq!(reply.enhanced, None);
+        // class must match the reply code
+        assert_eq!(
+            SmtpReply::parse("250 5.0.0 odd\r\n").unwrap().enhanced,
+            None
+        );
+        assert!(SmtpReply::parse("hello\r\n").is_err());
+    }
+
+    #[test]
+    fn test_queue_id() {
+        let id = |raw: &str| SmtpReply::parse(raw).unwrap().queue_id();
+        assert_eq!(
+            id("250 2.0.0 Ok: queued as 4Xyz1234\r\n").as_deref(),
+            Some("4Xyz1234")
+        );
+        assert_eq!(
+            id("250 OK id=1rABCD-0001-Xy\r\n").as_deref(),
+            Some("1rABCD-0001-Xy")
+        );
+        assert_eq!(
+            id("250 2.0.0 x8ABCDE123 Message accepted for delivery\r\n").as_deref(),
+            Some("x8ABCDE123")
+        );
+        assert_eq!(
+            id("250 Ok 0100018f-abcd\r\n").as_deref(),
+            Some("0100018f-abcd")
+        );
+        assert_eq!(
+            id("250 2.0.0 OK  1700000000 d2e1a72f.123 - gsmtp\r\n").as_deref(),
+            Some("d2e1a72f.123")
+        );
+        assert_eq!(id("250 Accepted\r\n"), None);
+        assert_eq!(id("451 4.3.0 queued as X\r\n"), None);
+    }
+}
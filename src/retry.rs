@@ -0,0 +1,121 @@
+//! Retry policy: exponential backoff with jitter for transient SMTP failures.
+
+use crate::errors::MailerError;
+use crate::utils::random_u64;
+
+/// When and how often a failed send is tried again.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct RetryPolicy {
+    /// Attempts in total, the first one included.
+    pub max_attempts: u32,
+    /// Delay after the first failure; doubled after each further one.
+    pub initial_backoff_ms: u64,
+    pub max_backoff_ms: u64,
+    /// Randomize each delay between half and all of its value.
+    pub jitter: bool,
+    /// Reply codes worth retrying. `None` retries every 4xx reply; connection failures
+    /// and timeouts are always retried, unless the body was sent (`MailerError::Unconfirmed`).
+    pub retryable_codes: Option<Vec<u16>>,
+    /// Give up once this much time has passed since the first attempt.
+    pub deadline_ms: Option<u64>,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 3,
+            initial_backoff_ms: 1_000,
+            max_backoff_ms: 30_000,
+            jitter: true,
+            retryable_codes: None,
+            deadline_ms: None,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Whether `error` is transient under this policy.
+    pub fn is_retryable(&self, error: &MailerError) -> bool {
+        match (&self.retryable_codes, error.reply()) {
+            (Some(codes), Some(reply)) => codes.contains(&reply.code),
+            _ => error.retryable(),
+        }
+    }
+
+    /// Delay after failed attempt number `attempt` (1-based).
+    pub fn backoff_ms(&self, attempt: u32) -> u64 {
+        let factor = 2u64.saturating_pow(attempt.saturating_sub(1));
+        let delay = self
+            .initial_backoff_ms
+            .saturating_mul(factor)
+            .min(self.max_backoff_ms);
+        if !self.jitter || delay == 0 {
+            return delay;
+        }
+        delay / 2 + random_u64() % (delay / 2 + 1)
+    }
+
+    /// Delay before trying again after attempt `attempt` failed with `error`, `elapsed_ms`
+    /// after the first one; `None` when the failure is permanent, the attempts are used up
+    /// or the deadline would pass.
+    pub fn next_delay(&self, attempt: u32, elapsed_ms: u64, error: &MailerError) -> Option<u64> {
+        if attempt >= self.max_attempts || !self.is_retryable(error) {
+            return None;
+        }
+        let delay = self.backoff_ms(attempt);
+        match self.deadline_ms {
+            Some(deadline) if elapsed_ms.saturating_add(delay) >= deadline => None,
+            _ => Some(delay),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::errors::{SmtpConnectionError, SmtpReplyError};
+    use crate::reply::SmtpReply;
+
+    fn reply_error(raw: &str) -> MailerError {
+        SmtpReplyError::new("MAIL FROM failed", SmtpReply::parse(raw).unwrap()).into()
+    }
+
+    #[test]
+    fn test_next_delay() {
+        let policy = RetryPolicy {
+            jitter: false,
+            deadline_ms: Some(10_000),
+            ..Default::default()
+        };
+        let transient = reply_error("451 4.3.0 try later\r\n");
+        assert_eq!(policy.next_delay(1, 0, &transient), Some(1_000));
+        assert_eq!(policy.next_delay(2, 0, &transient), Some(2_000));
+        assert_eq!(policy.next_delay(3, 0, &transient), None);
+        assert_eq!(policy.next_delay(2, 8_500, &transient), None);
+        assert_eq!(
+            policy.next_delay(1, 0, &reply_error("550 5.1.1 unknown\r\n")),
+            None
+        );
+        let lost: MailerError = SmtpConnectionError("reset".into()).into();
+        assert!(policy.is_retryable(&lost));
+
+        let only_421 = RetryPolicy {
+            retryable_codes: Some(vec![421]),
+            ..policy
+        };
+        assert!(!only_421.is_retryable(&transient));
+        assert!(only_421.is_retryable(&lost));
+    }
+
+    #[test]
+    fn test_backoff_jitter_bounds() {
+        let policy = RetryPolicy::default();
+        for _ in 0..20 {
+            let delay = policy.backoff_ms(3);
+            assert!((2_000..=4_000).contains(&delay), "{}", delay);
+        }
+        // capped at max_backoff_ms, even when the exponent overflows
+        assert!((15_000..=30_000).contains(&policy.backoff_ms(80)));
+    }
+}
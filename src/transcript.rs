@@ -0,0 +1,136 @@
+//! SMTP session transcript: timestamped client and server lines, secrets redacted.
+
+use crate::utils::{format_iso8601, now_millis};
+
+/// What to capture (off unless set on `WorkerMailerOptions.transcript`).
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct TranscriptOptions {
+    /// Replace each message body with its size. AUTH payloads are always redacted.
+    pub redact_body: bool,
+}
+
+impl Default for TranscriptOptions {
+    fn default() -> Self {
+        Self { redact_body: true }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Direction {
+    Client,
+    Server,
+    /// Written by the mailer itself (connects, TLS upgrades, redacted bodies).
+    Note,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct TranscriptLine {
+    /// Unix time in milliseconds.
+    pub at_ms: u64,
+    pub direction: Direction,
+    /// The line without its CRLF.
+    pub text: String,
+}
+
+/// Lines exchanged with the relay, in order.
+#[derive(Debug, Clone, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct Transcript {
+    pub lines: Vec<TranscriptLine>,
+}
+
+impl Transcript {
+    /// Records each CRLF-separated line of `data`.
+    pub fn push(&mut self, direction: Direction, data: &str) {
+        let at_ms = now_millis();
+        let data = data.strip_suffix("\r\n").unwrap_or(data);
+        self.lines
+            .extend(data.split("\r\n").map(|text| TranscriptLine {
+                at_ms,
+                direction,
+                text: text.to_string(),
+            }));
+    }
+
+    /// Lines recorded from index `start` on.
+    pub fn since(&self, start: usize) -> Transcript {
+        Transcript {
+            lines: self.lines.get(start..).unwrap_or_default().to_vec(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.lines.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.lines.is_empty()
+    }
+}
+
+/// One line per entry: `2024-01-01T00:00:00.000Z C: EHLO example.com`.
+impl std::fmt::Display for Transcript {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for line in &self.lines {
+            let tag = match line.direction {
+                Direction::Client => "C:",
+                Direction::Server => "S:",
+                Direction::Note => "--",
+            };
+            writeln!(f, "{} {} {}", format_iso8601(line.at_ms), tag, line.text)?;
+        }
+        Ok(())
+    }
+}
+
+/// `data` as it may be shown while authenticating: the mechanism name is kept, SASL
+/// payloads (which can carry the password) are masked.
+pub fn redact_auth(data: &str) -> String {
+    let data = data.strip_suffix("\r\n").unwrap_or(data);
+    data.split("\r\n")
+        .map(|line| {
+            let mut words = line.splitn(3, ' ');
+            match (words.next(), words.next(), words.next()) {
+                (Some(cmd), Some(mechanism), payload) if cmd.eq_ignore_ascii_case("AUTH") => {
+                    match payload {
+                        Some(_) => format!("{} {} ***", cmd, mechanism),
+                        None => line.to_string(),
+                    }
+                }
+                _ => "***".to_string(),
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("\r\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_redact_auth() {
+        assert_eq!(
+            redact_auth("AUTH PLAIN AHVzZXIAcGFzcw==\r\n"),
+            "AUTH PLAIN ***"
+        );
+        assert_eq!(redact_auth("AUTH LOGIN\r\n"), "AUTH LOGIN");
+        assert_eq!(redact_auth("cGFzcw==\r\n"), "***");
+    }
+
+    #[test]
+    fn test_push_splits_lines() {
+        let mut transcript = Transcript::default();
+        transcript.push(Direction::Server, "250-mx\r\n250 PIPELINING\r\n");
+        transcript.push(Direction::Client, "QUIT\r\n");
+        assert_eq!(transcript.len(), 3);
+        assert_eq!(transcript.since(2).lines[0].text, "QUIT");
+        assert!(transcript
+            .to_string()
+            .lines()
+            .nth(1)
+            .unwrap()
+            .ends_with("S: 250 PIPELINING"));
+    }
+}
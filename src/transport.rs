@@ -0,0 +1,353 @@
+//! Byte-stream transports the SMTP client runs over.
+//!
+//! `WorkerMailer` only talks to a [`Transport`], opened by a [`Connector`]. Implementations:
+//! Cloudflare sockets (workerd), tokio + rustls (feature `native`) and an in-memory duplex
+//! for tests (feature `testing`).
+
+use async_trait::async_trait;
+use std::io;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use worker::{ConnectionBuilder, SecureTransport, Socket};
+
+/// Connected byte stream (read, write, upgrade to TLS, close).
+#[async_trait(?Send)]
+pub trait Transport {
+    /// Read into `buf`; returns 0 on EOF.
+    async fn read(&mut self, buf: &mut [u8]) -> io::Result<usize>;
+    /// Write all of `data` and flush.
+    async fn write(&mut self, data: &[u8]) -> io::Result<()>;
+    /// Upgrade the connection to TLS (STARTTLS). `host` is the name to verify.
+    async fn start_tls(&mut self, host: &str) -> io::Result<()>;
+    /// Close the connection.
+    async fn close(&mut self) -> io::Result<()>;
+}
+
+/// Opens transports. `secure` asks for implicit TLS from the first byte.
+#[async_trait(?Send)]
+pub trait Connector {
+    async fn connect(&self, host: &str, port: u16, secure: bool) -> io::Result<Box<dyn Transport>>;
+}
+
+/// Connector used by `WorkerMailer::connect`: tokio on native builds with the
+/// `native` feature, Cloudflare sockets otherwise.
+pub fn default_connector() -> Box<dyn Connector> {
+    #[cfg(all(feature = "native", not(target_arch = "wasm32")))]
+    {
+        Box::new(TokioConnector::default())
+    }
+    #[cfg(not(all(feature = "native", not(target_arch = "wasm32"))))]
+    {
+        Box::new(CloudflareConnector)
+    }
+}
+
+fn not_connected() -> io::Error {
+    io::Error::new(io::ErrorKind::NotConnected, "transport is not connected")
+}
+
+fn worker_to_io(e: worker::Error) -> io::Error {
+    io::Error::other(e.to_string())
+}
+
+/// Cloudflare Workers TCP socket.
+pub struct CloudflareTransport {
+    socket: Option<Socket>,
+}
+
+impl CloudflareTransport {
+    pub fn new(socket: Socket) -> Self {
+        Self {
+            socket: Some(socket),
+        }
+    }
+
+    fn socket(&mut self) -> io::Result<&mut Socket> {
+        self.socket.as_mut().ok_or_else(not_connected)
+    }
+}
+
+#[async_trait(?Send)]
+impl Transport for CloudflareTransport {
+    async fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.socket()?.read(buf).await
+    }
+
+    async fn write(&mut self, data: &[u8]) -> io::Result<()> {
+        let socket = self.socket()?;
+        socket.write_all(data).await?;
+        socket.flush().await
+    }
+
+    async fn start_tls(&mut self, _host: &str) -> io::Result<()> {
+        let socket = self.socket.take().ok_or_else(not_connected)?;
+        self.socket = Some(socket.start_tls());
+        Ok(())
+    }
+
+    async fn close(&mut self) -> io::Result<()> {
+        if let Some(mut socket) = self.socket.take() {
+            socket.close().await.map_err(worker_to_io)?;
+        }
+        Ok(())
+    }
+}
+
+/// Opens sockets with `worker::ConnectionBuilder`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CloudflareConnector;
+
+#[async_trait(?Send)]
+impl Connector for CloudflareConnector {
+    async fn connect(&self, host: &str, port: u16, secure: bool) -> io::Result<Box<dyn Transport>> {
+        // `start_tls()` only works on sockets opened in "starttls" mode.
+        let secure_transport = if secure {
+            SecureTransport::On
+        } else {
+            SecureTransport::StartTls
+        };
+        let socket = ConnectionBuilder::new()
+            .allow_half_open(false)
+            .secure_transport(secure_transport)
+            .connect(host, port)
+            .map_err(worker_to_io)?;
+        // `connect` returns before the TCP handshake; wait for it so the caller's
+        // connect timeout covers it and refusals surface here.
+        socket.opened().await.map_err(worker_to_io)?;
+        Ok(Box::new(CloudflareTransport::new(socket)))
+    }
+}
+
+#[cfg(any(test, feature = "testing"))]
+pub use memory::{MemoryConnector, MemoryTransport};
+
+#[cfg(any(test, feature = "testing"))]
+mod memory {
+    use super::{not_connected, Connector, Transport};
+    use async_trait::async_trait;
+    use std::cell::RefCell;
+    use std::collections::VecDeque;
+    use std::io;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt, DuplexStream};
+
+    /// In-memory transport over a tokio duplex pipe, for driving the client against a
+    /// scripted server. `start_tls` is a no-op: the conversation stays in plaintext, so
+    /// this only exists in tests and with the `testing` feature.
+    pub struct MemoryTransport {
+        stream: Option<DuplexStream>,
+    }
+
+    impl MemoryTransport {
+        /// Create a transport and the server end of its pipe.
+        pub fn pair(max_buf_size: usize) -> (Self, DuplexStream) {
+            let (client, server) = tokio::io::duplex(max_buf_size);
+            (
+                Self {
+                    stream: Some(client),
+                },
+                server,
+            )
+        }
+
+        fn stream(&mut self) -> io::Result<&mut DuplexStream> {
+            self.stream.as_mut().ok_or_else(not_connected)
+        }
+    }
+
+    #[async_trait(?Send)]
+    impl Transport for MemoryTransport {
+        async fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.stream()?.read(buf).await
+        }
+
+        async fn write(&mut self, data: &[u8]) -> io::Result<()> {
+            let stream = self.stream()?;
+            stream.write_all(data).await?;
+            stream.flush().await
+        }
+
+        async fn start_tls(&mut self, _host: &str) -> io::Result<()> {
+            self.stream()?;
+            Ok(())
+        }
+
+        async fn close(&mut self) -> io::Result<()> {
+            if let Some(mut stream) = self.stream.take() {
+                stream.shutdown().await?;
+            }
+            Ok(())
+        }
+    }
+
+    /// Hands out pre-built [`MemoryTransport`]s in order, one per `connect`.
+    #[derive(Default)]
+    pub struct MemoryConnector {
+        transports: RefCell<VecDeque<MemoryTransport>>,
+    }
+
+    impl MemoryConnector {
+        pub fn new(transports: Vec<MemoryTransport>) -> Self {
+            Self {
+                transports: RefCell::new(transports.into()),
+            }
+        }
+
+        pub fn push(&self, transport: MemoryTransport) {
+            self.transports.borrow_mut().push_back(transport);
+        }
+    }
+
+    #[async_trait(?Send)]
+    impl Connector for MemoryConnector {
+        async fn connect(
+            &self,
+            host: &str,
+            port: u16,
+            _secure: bool,
+        ) -> io::Result<Box<dyn Transport>> {
+            match self.transports.borrow_mut().pop_front() {
+                Some(t) => Ok(Box::new(t)),
+                None => Err(io::Error::new(
+                    io::ErrorKind::ConnectionRefused,
+                    format!("no memory transport left for {}:{}", host, port),
+                )),
+            }
+        }
+    }
+}
+
+#[cfg(all(feature = "native", not(target_arch = "wasm32")))]
+pub use native::{TokioConnector, TokioTransport};
+
+#[cfg(all(feature = "native", not(target_arch = "wasm32")))]
+mod native {
+    use super::{not_connected, Connector, Transport};
+    use async_trait::async_trait;
+    use std::io;
+    use std::sync::Arc;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpStream;
+    use tokio_rustls::client::TlsStream;
+    use tokio_rustls::rustls::pki_types::ServerName;
+    use tokio_rustls::rustls::{ClientConfig, RootCertStore};
+    use tokio_rustls::TlsConnector;
+
+    enum Stream {
+        Plain(TcpStream),
+        Tls(Box<TlsStream<TcpStream>>),
+    }
+
+    /// Native TCP transport with rustls for implicit TLS and STARTTLS.
+    pub struct TokioTransport {
+        stream: Option<Stream>,
+        tls: Arc<ClientConfig>,
+    }
+
+    impl TokioTransport {
+        pub async fn connect(
+            host: &str,
+            port: u16,
+            secure: bool,
+            tls: Arc<ClientConfig>,
+        ) -> io::Result<Self> {
+            let tcp = TcpStream::connect((host, port)).await?;
+            let mut transport = Self {
+                stream: Some(Stream::Plain(tcp)),
+                tls,
+            };
+            if secure {
+                transport.start_tls(host).await?;
+            }
+            Ok(transport)
+        }
+    }
+
+    #[async_trait(?Send)]
+    impl Transport for TokioTransport {
+        async fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            match self.stream.as_mut().ok_or_else(not_connected)? {
+                Stream::Plain(s) => s.read(buf).await,
+                Stream::Tls(s) => s.read(buf).await,
+            }
+        }
+
+        async fn write(&mut self, data: &[u8]) -> io::Result<()> {
+            match self.stream.as_mut().ok_or_else(not_connected)? {
+                Stream::Plain(s) => {
+                    s.write_all(data).await?;
+                    s.flush().await
+                }
+                Stream::Tls(s) => {
+                    s.write_all(data).await?;
+                    s.flush().await
+                }
+            }
+        }
+
+        async fn start_tls(&mut self, host: &str) -> io::Result<()> {
+            let tcp = match self.stream.take().ok_or_else(not_connected)? {
+                Stream::Plain(s) => s,
+                tls @ Stream::Tls(_) => {
+                    self.stream = Some(tls);
+                    return Err(io::Error::other("connection is already using TLS"));
+                }
+            };
+            let name = ServerName::try_from(host.to_string())
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+            let tls = TlsConnector::from(self.tls.clone())
+                .connect(name, tcp)
+                .await?;
+            self.stream = Some(Stream::Tls(Box::new(tls)));
+            Ok(())
+        }
+
+        async fn close(&mut self) -> io::Result<()> {
+            match self.stream.take() {
+                Some(Stream::Plain(mut s)) => s.shutdown().await,
+                Some(Stream::Tls(mut s)) => s.shutdown().await,
+                None => Ok(()),
+            }
+        }
+    }
+
+    /// Opens [`TokioTransport`]s, verifying servers against the webpki roots by default.
+    #[derive(Clone)]
+    pub struct TokioConnector {
+        tls: Arc<ClientConfig>,
+    }
+
+    impl TokioConnector {
+        /// Use a custom rustls configuration (private CA, client certificates...).
+        pub fn with_config(tls: Arc<ClientConfig>) -> Self {
+            Self { tls }
+        }
+    }
+
+    impl Default for TokioConnector {
+        fn default() -> Self {
+            let roots = RootCertStore {
+                roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+            };
+            let config = ClientConfig::builder_with_provider(Arc::new(
+                tokio_rustls::rustls::crypto::ring::default_provider(),
+            ))
+            .with_safe_default_protocol_versions()
+            .expect("ring provider supports the default protocol versions")
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+            Self::with_config(Arc::new(config))
+        }
+    }
+
+    #[async_trait(?Send)]
+    impl Connector for TokioConnector {
+        async fn connect(
+            &self,
+            host: &str,
+            port: u16,
+            secure: bool,
+        ) -> io::Result<Box<dyn Transport>> {
+            let t = TokioTransport::connect(host, port, secure, self.tls.clone()).await?;
+            Ok(Box::new(t))
+        }
+    }
+}
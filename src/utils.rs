@@ -1,7 +1,11 @@
 //! Encoding, decoding, email validation (mirror of TS utils).
 
+use futures_util::future::{select, Either};
 use regex::Regex;
+use std::future::Future;
+use std::net::{IpAddr, Ipv6Addr};
 use std::str;
+use std::time::Duration;
 
 /// Validates email format (RFC 5322 simplified).
 pub fn is_valid_email(email: &str) -> bool {
@@ -30,7 +34,7 @@ pub fn is_valid_email(email: &str) -> bool {
     if !domain.contains('.') {
         return false;
     }
-    let tld = domain.split('.').last().unwrap_or("");
+    let tld = domain.rsplit('.').next().unwrap_or("");
     tld.len() >= 2
 }
 
@@ -78,9 +82,8 @@ pub fn encode_quoted_printable(text: &str, line_length: usize) -> String {
             }
         } else {
             let is_whitespace = byte == 0x20 || byte == 0x09;
-            let next_is_line_break = i + 1 >= bytes.len()
-                || bytes[i + 1] == 0x0a
-                || bytes[i + 1] == 0x0d;
+            let next_is_line_break =
+                i + 1 >= bytes.len() || bytes[i + 1] == 0x0a || bytes[i + 1] == 0x0d;
             let needs_encoding = (byte < 32 && (byte != 0x20 && byte != 0x09))
                 || byte > 126
                 || byte == 61
@@ -124,6 +127,244 @@ pub fn encode_header(text: &str) -> String {
     format!("=?UTF-8?Q?{}?=", encoded)
 }
 
+/// RFC 3461 xtext: `+` followed by two upper-case hex digits for `+`, `=`, and bytes
+/// outside `!`..`~`.
+pub fn encode_xtext(text: &str) -> String {
+    text.bytes()
+        .map(|b| {
+            if (33..=126).contains(&b) && b != b'+' && b != b'=' {
+                char::from(b).to_string()
+            } else {
+                format!("+{:02X}", b)
+            }
+        })
+        .collect()
+}
+
+/// `application/x-www-form-urlencoded` body from key/value pairs.
+pub fn form_urlencode(pairs: &[(&str, &str)]) -> String {
+    fn encode_component(s: &str) -> String {
+        s.bytes()
+            .map(|b| match b {
+                b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'*' => {
+                    char::from(b).to_string()
+                }
+                b' ' => "+".to_string(),
+                _ => format!("%{:02X}", b),
+            })
+            .collect()
+    }
+    pairs
+        .iter()
+        .map(|(k, v)| format!("{}={}", encode_component(k), encode_component(v)))
+        .collect::<Vec<_>>()
+        .join("&")
+}
+
+/// Normalizes an EHLO/HELO identity (RFC 5321 section 4.1.3): a fully qualified domain
+/// name, or an address literal (`[192.0.2.1]`, `[IPv6:2001:db8::1]`). A bare IP address
+/// is turned into its literal. Returns `None` when `name` is neither.
+pub fn normalize_client_hostname(name: &str) -> Option<String> {
+    let name = name.trim();
+    if let Some(inner) = name.strip_prefix('[').and_then(|n| n.strip_suffix(']')) {
+        let valid = match inner.get(..5) {
+            Some(tag) if tag.eq_ignore_ascii_case("IPv6:") => {
+                inner[5..].parse::<Ipv6Addr>().is_ok()
+            }
+            _ => inner.parse::<std::net::Ipv4Addr>().is_ok(),
+        };
+        return valid.then(|| name.to_string());
+    }
+    match name.parse::<IpAddr>() {
+        Ok(IpAddr::V4(ip)) => return Some(format!("[{}]", ip)),
+        Ok(IpAddr::V6(ip)) => return Some(format!("[IPv6:{}]", ip)),
+        Err(_) => {}
+    }
+    let host = name.strip_suffix('.').unwrap_or(name);
+    let labels: Vec<&str> = host.split('.').collect();
+    let valid = host.len() <= 253
+        && labels.len() >= 2
+        && labels.iter().all(|l| {
+            !l.is_empty()
+                && l.len() <= 63
+                && !l.starts_with('-')
+                && !l.ends_with('-')
+                && l.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-')
+        })
+        // The top-level label of a domain is never all digits.
+        && !labels[labels.len() - 1].bytes().all(|b| b.is_ascii_digit());
+    valid.then(|| host.to_ascii_lowercase())
+}
+
+/// Milliseconds since the Unix epoch (JS clock on wasm, system clock elsewhere).
+pub fn now_millis() -> u64 {
+    #[cfg(target_arch = "wasm32")]
+    {
+        worker::Date::now().as_millis()
+    }
+    #[cfg(not(target_arch = "wasm32"))]
+    {
+        std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.as_millis() as u64)
+            .unwrap_or(0)
+    }
+}
+
+/// Random `u64` from the platform RNG (0 if it is unavailable).
+pub fn random_u64() -> u64 {
+    let mut bytes = [0u8; 8];
+    getrandom::getrandom(&mut bytes).unwrap_or_default();
+    u64::from_le_bytes(bytes)
+}
+
+/// Sleeps for `ms` milliseconds (JS timer on wasm, tokio timer elsewhere).
+pub async fn sleep(ms: u64) {
+    #[cfg(target_arch = "wasm32")]
+    worker::Delay::from(Duration::from_millis(ms)).await;
+    #[cfg(not(target_arch = "wasm32"))]
+    tokio::time::sleep(Duration::from_millis(ms)).await;
+}
+
+/// Runs `fut`, or returns `None` if `ms` milliseconds pass first. `ms == 0` waits forever.
+pub async fn timeout<F: Future>(ms: u64, fut: F) -> Option<F::Output> {
+    if ms == 0 {
+        return Some(fut.await);
+    }
+    let fut = std::pin::pin!(fut);
+    let timer = std::pin::pin!(sleep(ms));
+    match select(fut, timer).await {
+        Either::Left((output, _)) => Some(output),
+        Either::Right(_) => None,
+    }
+}
+
+/// Converts days since the Unix epoch to (year, month, day) in the proleptic Gregorian calendar.
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+/// RFC 5322 date-time in UTC (e.g. `Thu, 01 Jan 1970 00:00:00 +0000`).
+pub fn format_rfc2822_date(millis: u64) -> String {
+    const DAYS: [&str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+    const MONTHS: [&str; 12] = [
+        "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+    ];
+    let secs = (millis / 1000) as i64;
+    let days = secs.div_euclid(86_400);
+    let rem = secs.rem_euclid(86_400);
+    let (year, month, day) = civil_from_days(days);
+    format!(
+        "{}, {:02} {} {} {:02}:{:02}:{:02} +0000",
+        DAYS[days.rem_euclid(7) as usize],
+        day,
+        MONTHS[(month - 1) as usize],
+        year,
+        rem / 3600,
+        rem % 3600 / 60,
+        rem % 60
+    )
+}
+
+/// RFC 3339 timestamp in UTC with milliseconds (e.g. `1970-01-01T00:00:00.000Z`).
+pub fn format_iso8601(millis: u64) -> String {
+    let secs = (millis / 1000) as i64;
+    let rem = secs.rem_euclid(86_400);
+    let (year, month, day) = civil_from_days(secs.div_euclid(86_400));
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year,
+        month,
+        day,
+        rem / 3600,
+        rem % 3600 / 60,
+        rem % 60,
+        millis % 1000
+    )
+}
+
+/// RFC 3339 timestamp in UTC, whole seconds (e.g. `1970-01-01T00:00:00Z`).
+pub fn format_rfc3339(millis: u64) -> String {
+    let mut s = format_iso8601(millis);
+    s.replace_range(19..23, "");
+    s
+}
+
+/// Days since the Unix epoch of a proleptic Gregorian date (inverse of `civil_from_days`).
+fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let yoe = year.rem_euclid(400);
+    let mp = i64::from((month + 9) % 12);
+    let doy = (153 * mp + 2) / 5 + i64::from(day) - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146_097 + doe - 719_468
+}
+
+/// Parses an RFC 3339 date-time (`2026-10-17T09:00:00Z`, `...09:00:00.5-03:00`) into
+/// Unix milliseconds; `None` when malformed or before 1970.
+pub fn parse_rfc3339(s: &str) -> Option<u64> {
+    let num = |range: std::ops::Range<usize>| -> Option<u32> {
+        let part = s.get(range)?;
+        part.bytes()
+            .all(|b| b.is_ascii_digit())
+            .then(|| part.parse().ok())?
+    };
+    let b = s.as_bytes();
+    if b.len() < 20 || b[4] != b'-' || b[7] != b'-' || !matches!(b[10], b'T' | b't' | b' ') {
+        return None;
+    }
+    if b[13] != b':' || b[16] != b':' {
+        return None;
+    }
+    let (year, month, day) = (num(0..4)?, num(5..7)?, num(8..10)?);
+    let (hour, minute, second) = (num(11..13)?, num(14..16)?, num(17..19)?);
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) {
+        return None;
+    }
+    if hour > 23 || minute > 59 || second > 60 {
+        return None;
+    }
+    let mut rest = &s[19..];
+    let mut millis = 0;
+    if let Some(frac) = rest.strip_prefix('.') {
+        let digits = frac.bytes().take_while(u8::is_ascii_digit).count();
+        if digits == 0 {
+            return None;
+        }
+        let padded = format!("{:0<3}", &frac[..digits.min(3)]);
+        millis = padded.parse::<i64>().ok()?;
+        rest = &frac[digits..];
+    }
+    let offset_secs = match rest {
+        "Z" | "z" => 0,
+        _ if rest.len() == 6 && rest.as_bytes()[3] == b':' => {
+            let sign = match rest.as_bytes()[0] {
+                b'+' => 1,
+                b'-' => -1,
+                _ => return None,
+            };
+            let hours: i64 = rest.get(1..3)?.parse().ok()?;
+            let minutes: i64 = rest.get(4..6)?.parse().ok()?;
+            sign * (hours * 3600 + minutes * 60)
+        }
+        _ => return None,
+    };
+    let secs = days_from_civil(i64::from(year), month, day) * 86_400
+        + i64::from(hour * 3600 + minute * 60 + second)
+        - offset_secs;
+    u64::try_from(secs * 1000 + millis).ok()
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -134,4 +375,55 @@ mod tests {
         assert!(!is_valid_email(""));
         assert!(!is_valid_email("invalid"));
     }
+
+    #[test]
+    fn test_encode_xtext() {
+        assert_eq!(
+            encode_xtext("a+b=c d@example.com"),
+            "a+2Bb+3Dc+20d@example.com"
+        );
+    }
+
+    #[test]
+    fn test_normalize_client_hostname() {
+        assert_eq!(
+            normalize_client_hostname("Mail.Example.com").as_deref(),
+            Some("mail.example.com")
+        );
+        assert_eq!(
+            normalize_client_hostname("127.0.0.1").as_deref(),
+            Some("[127.0.0.1]")
+        );
+        assert_eq!(
+            normalize_client_hostname("[IPv6:::1]").as_deref(),
+            Some("[IPv6:::1]")
+        );
+        assert_eq!(
+            normalize_client_hostname("::1").as_deref(),
+            Some("[IPv6:::1]")
+        );
+        assert_eq!(normalize_client_hostname("localhost"), None);
+        assert_eq!(normalize_client_hostname("[999.0.0.1]"), None);
+        assert_eq!(normalize_client_hostname("bad_host.example.com"), None);
+    }
+
+    #[test]
+    fn test_format_rfc2822_date() {
+        assert_eq!(format_rfc2822_date(0), "Thu, 01 Jan 1970 00:00:00 +0000");
+        assert_eq!(format_iso8601(1_500), "1970-01-01T00:00:01.500Z");
+        assert_eq!(format_rfc3339(1_791_018_000_999), "2026-10-03T09:00:00Z");
+        assert_eq!(
+            parse_rfc3339("2026-10-03T09:00:00Z"),
+            Some(1_791_018_000_000)
+        );
+        assert_eq!(
+            parse_rfc3339("2026-10-03T06:00:00.25-03:00"),
+            Some(1_791_018_000_250)
+        );
+        assert_eq!(parse_rfc3339("2026-10-03 09:00"), None);
+        assert_eq!(
+            format_rfc2822_date(1_791_018_000_000),
+            "Sat, 03 Oct 2026 09:00:00 +0000"
+        );
+    }
 }